@@ -0,0 +1,46 @@
+import timeit
+
+import time
+
+from rbloom import Bloom
+
+# large enough (~450 MB) that random probes miss the CPU caches
+NUMBER = 250_000_000
+SAMPLE = 1_000_000
+
+
+def format_time(time_ns: float) -> str:
+    return f"{time_ns / 1000:.04} us"
+
+
+def main():
+    for blocked in [False, True]:
+        b = Bloom(NUMBER, 0.001, blocked=blocked)
+        objects = [i + 0.5 for i in range(SAMPLE)]
+        b.update(objects)
+        absent = [-i - 0.5 for i in range(SAMPLE)]
+
+        results = timeit.repeat(
+            stmt="for o in objects: o in b",
+            globals={"b": b, "objects": objects},
+            timer=time.perf_counter_ns,
+            number=1,
+            repeat=5,
+        )
+        print(f"Time to check if a present object is present (blocked={blocked}):")
+        print(format_time(min(results) / SAMPLE))
+
+        results = timeit.repeat(
+            stmt="for o in absent: o in b",
+            globals={"b": b, "absent": absent},
+            timer=time.perf_counter_ns,
+            number=1,
+            repeat=5,
+        )
+        print(f"Time to check if an absent object is present (blocked={blocked}):")
+        print(format_time(min(results) / SAMPLE))
+        del b
+
+
+if __name__ == "__main__":
+    main()
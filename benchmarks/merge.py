@@ -0,0 +1,36 @@
+import timeit
+
+import time
+
+from rbloom import Bloom
+
+# large enough (~1.8 GB) that merging is split over all cores
+NUMBER = 1_000_000_000
+SAMPLE = 1_000_000
+
+
+def format_time(time_ns: float) -> str:
+    return f"{time_ns / 1_000_000:.04} ms"
+
+
+def main():
+    a = Bloom(NUMBER, 0.001)
+    b = Bloom(NUMBER, 0.001)
+    a.update(i + 0.5 for i in range(SAMPLE))
+    b.update(-i - 0.5 for i in range(SAMPLE))
+    print(f"Filter size: {a.size_in_bits / 8 / 1e9:.3} GB")
+
+    for stmt in ["a |= b", "a &= b"]:
+        results = timeit.repeat(
+            stmt=stmt,
+            globals={"a": a.copy(), "b": b},
+            timer=time.perf_counter_ns,
+            number=1,
+            repeat=5,
+        )
+        print(f"Time for {stmt}:")
+        print(format_time(min(results)))
+
+
+if __name__ == "__main__":
+    main()
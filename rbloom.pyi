@@ -1,5 +1,5 @@
 import os
-from typing import Any, Callable, Iterable, Union, final
+from typing import Any, Callable, Iterable, Iterator, Optional, Union, final
 
 
 @final
@@ -8,28 +8,197 @@ class Bloom:
     # expected_items:  max number of items to be added to the filter
     # false_positive_rate:  max false positive rate of the filter
     # hash_func:  optional argument, see section "Cryptographic security"
+    # blocked:  keep each item's bits in one cache line, see "Blocked filters"
+    # warn_at_fill:  emit a UserWarning once this fraction of bits is set
+    # hash_id:  name of hash_func, compared instead of it, see "Persistence"
+    # on_full:  "ignore", "reject" (raise) or "rotate" (clear) once the
+    #           filter holds expected_items items
+    # exact:  raise ValueError if the filter can't be sized to meet
+    #         false_positive_rate within 1%, e.g. because it's tiny
+    # journal_path:  append the hash of every added item to this file, see
+    #                "Persistence"; needs a custom hash_func
     def __init__(self, expected_items: int, false_positive_rate: float,
-                 hash_func=__builtins__.hash) -> None: ...
+                 hash_func=__builtins__.hash, *, blocked: bool = False,
+                 warn_at_fill: Optional[float] = None,
+                 hash_id: Optional[str] = None,
+                 on_full: str = "ignore",
+                 exact: bool = False,
+                 journal_path: Optional[Union[str, bytes, os.PathLike]] = None) -> None: ...
 
     # number of buckets in the filter
     @property
     def size_in_bits(self) -> int: ...
 
+    # whether the filter was created with blocked=True
+    @property
+    def blocked(self) -> bool: ...
+
     # retrieve the hash_func given to __init__
     @property
-    def hash_func(self) -> Callable[[Any], int]: ...
+    def hash_func(self) -> Callable[[Any], Union[int, bytes]]: ...
+
+    # hash_id given to __init__ or loaded along with the filter
+    @property
+    def hash_id(self) -> Optional[str]: ...
 
-    # estimated number of items in the filter
+    # on_full given to __init__
+    @property
+    def on_full(self) -> str: ...
+
+    # expected_items given to __init__
+    @property
+    def expected_items(self) -> int: ...
+
+    # design rate, as given to __init__ or updated by shrink
+    @property
+    def false_positive_rate(self) -> float: ...
+
+    # estimated number of items in the filter, accurate up to several
+    # times expected_items and capped once the filter is saturated
     @property
     def approx_items(self) -> float: ...
 
+    # number of hash functions that would be best for the current size
+    # and approx_items
+    def optimal_num_hashes(self) -> int: ...
+
+    # warn if the filter is so full that it would do noticeably better
+    # with optimal_num_hashes() hash functions
+    def retune(self) -> None: ...
+
+    # number of additional items before the false positive rate exceeds
+    # false_positive_rate, 0 if it already does
+    def remaining_capacity(self) -> int: ...
+
+    # size_in_bits, k, bits_set, fill_ratio, expected_bits_set,
+    # approx_items, current_false_positive_rate and false_positive_rate
+    def stats(self) -> dict[str, Union[int, float]]: ...
+
+    # best achievable false_positive_rate and number of hash functions
+    # for a given memory budget, without allocating a filter; a filter
+    # created with that rate fits into size_in_bits
+    @classmethod
+    def rate_for_memory(cls, expected_items: int,
+                        size_in_bits: int) -> tuple[float, int]: ...
+
+    # largest expected_items for which a filter with false_positive_rate
+    # fits into size_in_bits, and its number of hash functions, without
+    # allocating a filter
+    @classmethod
+    def capacity_for(cls, size_in_bits: int,
+                     false_positive_rate: float) -> tuple[int, int]: ...
+
+    # empty filter of exactly this size (a multiple of 8) and number of
+    # hash functions, bypassing the sizing formula
+    @classmethod
+    def with_size(cls, size_in_bits: int, num_hashes: int,
+                  hash_func=__builtins__.hash) -> Bloom: ...
+
+    # hash samples into a scratch filter and report fill_ratio,
+    # expected_fill_ratio, chi_squared, degrees_of_freedom and
+    # collision_fraction, see section "Cryptographic security"
+    @classmethod
+    def test_hash_quality(cls, hash_func: Callable[[Any], Union[int, bytes]],
+                          samples: Iterable,
+                          false_positive_rate: float = 0.01) -> dict: ...
+
+    # value of an individual bit, for inspecting the filter
+    def __getitem__(self, index: int) -> bool: ...
+
+    # whether k, blocked and the bits are equal, whatever the hash_funcs
+    def structurally_equal(self, other: Bloom, /) -> bool: ...
+
+    # indices of the k bits that adding obj would set (possibly with
+    # repeats), without reading or changing the filter
+    def indices_for(self, obj: Any, /) -> list[int]: ...
+
+    # add or check an item by its precomputed hash, i.e. hash_func(obj)
+    def add_hash(self, h: Union[int, bytes], /) -> None: ...
+    def contains_hash(self, h: Union[int, bytes], /) -> bool: ...
+
+    # add each line of a text file as a str, without its line ending,
+    # calling progress as add_batch does; encodings that aren't compatible
+    # with ASCII, such as UTF-16, raise ValueError
+    def add_lines(self, filepath: Union[str, bytes, os.PathLike],
+                  encoding: str = "utf-8", *,
+                  skip_empty: bool = False,
+                  progress: Optional[Callable[[int], Any]] = None,
+                  progress_every: int = 100_000) -> None: ...
+
+    # fraction of the items that are in the filter, looked up in one pass
+    def contains_fraction(self, items: Iterable, /) -> float: ...
+
+    # fraction of items that were never added but appear to be in the filter
+    def measured_false_positive_rate(self, known_absent_items: Iterable,
+                                     /) -> float: ...
+
+    # SHA-256 of k, blocked and the bits, equal for equal filters
+    def fingerprint(self) -> bytes: ...
+
+    # empty and resize as if created with these arguments, keeping
+    # hash_func and blocked
+    def reconfigure(self, expected_items: int,
+                    false_positive_rate: float, /) -> None: ...
+
+    # fold to 1/factor of the size, see section "Shrinking filters"
+    def shrink(self, factor: int, /) -> None: ...
+
+    # union with a filter that only differs in size, after folding the
+    # larger one (or both) to the largest size both can be shrunk to
+    def merge(self, other: Bloom, /) -> Bloom: ...
+
+    # new, empty filter with all of other's parameters and its hash_func
+    @classmethod
+    def empty_like(cls, other: Bloom, /) -> Bloom: ...
+
+    # new filter with template's parameters and hash_func holding the keys,
+    # which should be the items added to this filter
+    def rebuild_compatible_with(self, template: Bloom,
+                                keys: Iterable[Any], /) -> Bloom: ...
+
+    # build a filter from an array of precomputed 64-bit hashes, such as a
+    # numpy int64/uint64 array, using each hash as add_hash would and
+    # calling progress as add_batch does
+    @classmethod
+    def from_hashes(cls, hashes: Any, expected_items: int,
+                    false_positive_rate: float,
+                    hash_func=__builtins__.hash, *,
+                    blocked: bool = False,
+                    hash_id: Optional[str] = None,
+                    progress: Optional[Callable[[int], Any]] = None,
+                    progress_every: int = 100_000) -> Bloom: ...
+
+    # add each argument as an item, never iterating over it, unlike update:
+    # add_all("foo", "bar") adds "foo" and "bar", update("foo", "bar") adds
+    # "f", "o", "b", "a" and "r"
+    def add_all(self, *items: Any) -> None: ...
+
+    # make __contains__ return False for obj from now on, whatever its bits
+    # say; only for keys that were never added, or they become false
+    # negatives. Exclusions aren't saved and are dropped by clear
+    def add_exclusion(self, obj: Any, /) -> None: ...
+
+    # add every item of an iterable; with count_collisions=True, return
+    # the number of items whose bits were all already set. progress is
+    # called with the number of items added so far every progress_every
+    # items and once at the end
+    def add_batch(self, items: Iterable, /,
+                  count_collisions: bool = False, *,
+                  progress: Optional[Callable[[int], Any]] = None,
+                  progress_every: int = 100_000) -> Optional[int]: ...
+
     # load from file, see section "Persistence"
     @classmethod
-    def load(cls, filepath: Union[str, bytes, os.PathLike], hash_func: Callable[[Any], int]) -> Bloom: ...
+    def load(cls, filepath: Union[str, bytes, os.PathLike], hash_func: Callable[[Any], Union[int, bytes]]) -> Bloom: ...
 
     # load from bytes(), see section "Persistence"
     @classmethod
-    def load_bytes(cls, data: bytes, hash_func: Callable[[Any], int]) -> Bloom: ...
+    def load_bytes(cls, data: bytes, hash_func: Callable[[Any], Union[int, bytes]]) -> Bloom: ...
+
+    # parameters of a serialized filter read from its header alone, see
+    # section "Persistence"
+    @classmethod
+    def inspect_bytes(cls, data: bytes, /) -> dict: ...
 
     # save to file, see section "Persistence"
     def save(self, filepath: Union[str, bytes, os.PathLike]) -> None: ...
@@ -37,6 +206,49 @@ class Bloom:
     # save to a bytes(), see section "Persistence"
     def save_bytes(self) -> bytes: ...
 
+    # load from a file object with a checksum, see section "Persistence"
+    @classmethod
+    def load_from(cls, fileobj: Any, hash_func: Callable[[Any], Union[int, bytes]],
+                  verify: bool = True) -> Bloom: ...
+
+    # save to a file object with a checksum, see section "Persistence"
+    def save_to(self, fileobj: Any) -> None: ...
+
+    # reassemble the output of to_shards, see section "Persistence"
+    @classmethod
+    def from_shards(cls, shards: Iterable[bytes],
+                    hash_func: Callable[[Any], Union[int, bytes]]) -> Bloom: ...
+
+    # the output of save_bytes in n pieces, see section "Persistence"
+    def to_shards(self, n: int, /) -> list[bytes]: ...
+
+    # the output of save_bytes in pieces, see section "Persistence"
+    def iter_chunks(self, chunk_size: int = 1 << 20) -> Iterator[bytes]: ...
+
+    # load from file and apply a delta, see section "Persistence"
+    @classmethod
+    def apply_delta(cls, base_filepath: Union[str, bytes, os.PathLike],
+                    delta_filepath: Union[str, bytes, os.PathLike],
+                    hash_func: Callable[[Any], Union[int, bytes]]) -> Bloom: ...
+
+    # save the bytes that changed since previous, see section "Persistence"
+    def save_delta(self, filepath: Union[str, bytes, os.PathLike],
+                   previous: Bloom, /) -> None: ...
+
+    # load a snapshot and add the hashes in the journal, see section
+    # "Persistence"; the result goes on appending to the journal
+    @classmethod
+    def replay(cls, snapshot_path: Union[str, bytes, os.PathLike],
+               journal_path: Union[str, bytes, os.PathLike],
+               hash_func: Callable[[Any], Union[int, bytes]]) -> Bloom: ...
+
+    # save a snapshot and empty the journal, see section "Persistence"
+    def checkpoint(self, snapshot_path: Union[str, bytes, os.PathLike],
+                   /) -> None: ...
+
+    # always raises TypeError, use CountingBloom to remove items
+    def difference_update(self, *others: Iterable) -> None: ...
+
     #####################################################################
     #                    ALL SUBSEQUENT METHODS ARE                     #
     #              EQUIVALENT TO THE CORRESPONDING METHODS              #
@@ -51,13 +263,15 @@ class Bloom:
 
     def __repr__(self) -> str: ...                    # basic info
 
-    def __or__(self, other: Bloom) -> Bloom: ...      # self | other
+    def _repr_html_(self) -> str: ...                 # summary for Jupyter
+
+    def __or__(self, other: Union[Iterable, Bloom]) -> Bloom: ...   # self | other
 
-    def __ior__(self, other: Bloom) -> None: ...      # self |= other
+    def __ior__(self, other: Union[Iterable, Bloom]) -> None: ...   # self |= other
 
-    def __and__(self, other: Bloom) -> Bloom: ...     # self & other
+    def __and__(self, other: Union[Iterable, Bloom]) -> Bloom: ...  # self & other
 
-    def __iand__(self, other: Bloom) -> None: ...     # self &= other
+    def __iand__(self, other: Union[Iterable, Bloom]) -> None: ...  # self &= other
 
     # extension of __or__
     def union(self, *others: Union[Iterable, Bloom]) -> Bloom: ...
@@ -86,3 +300,142 @@ class Bloom:
     def clear(self) -> None: ...                          # remove all items
 
     def copy(self) -> Bloom: ...                          # duplicate self
+
+
+@final
+class BloomSet:
+
+    # compatible filters, see section "Querying many filters"
+    def __init__(self, filters: Iterable[Bloom] = ()) -> None: ...
+
+    # add a filter, which must be compatible with the others
+    def append(self, bloom: Bloom, /) -> None: ...
+
+    # indices of the filters that contain obj
+    def which_contain(self, obj: Any, /) -> list[int]: ...
+
+    def __len__(self) -> int: ...
+
+    def __getitem__(self, index: int) -> Bloom: ...
+
+    def __repr__(self) -> str: ...                    # basic info
+
+
+@final
+class UnionView:
+
+    # compatible filters, see section "Querying many filters"
+    def __init__(self, filters: Iterable[Bloom], /) -> None: ...
+
+    # whether any of the filters contains obj
+    def __contains__(self, obj: Any) -> bool: ...
+
+    def __len__(self) -> int: ...
+
+    def __repr__(self) -> str: ...                    # basic info
+
+
+@final
+class CountingBloom:
+
+    # same arguments as Bloom, but uses a byte-sized counter per bit
+    def __init__(self, expected_items: int, false_positive_rate: float,
+                 hash_func=__builtins__.hash) -> None: ...
+
+    # number of counters in the filter
+    @property
+    def num_counters(self) -> int: ...
+
+    # retrieve the hash_func given to __init__
+    @property
+    def hash_func(self) -> Callable[[Any], Union[int, bytes]]: ...
+
+    def add(self, obj: Any, /) -> None: ...
+
+    def __contains__(self, obj: Any) -> bool: ...
+
+    # remove keys that were added before, see section "Counting filters"
+    def difference_update(self, keys: Iterable, /) -> None: ...
+
+    def __bool__(self) -> bool: ...                   # False if empty
+
+    def __repr__(self) -> str: ...                    # basic info
+
+    def clear(self) -> None: ...                      # remove all items
+
+    def copy(self) -> CountingBloom: ...              # duplicate self
+
+
+@final
+class StableBloom:
+
+    # same arguments as CountingBloom, plus the number of counters that
+    # each add decrements and the value it sets an item's counters to,
+    # see section "Stable filters"
+    def __init__(self, expected_items: int, false_positive_rate: float,
+                 hash_func=__builtins__.hash, *,
+                 decrements: Optional[int] = None,
+                 max_value: int = 3) -> None: ...
+
+    # number of counters in the filter
+    @property
+    def num_counters(self) -> int: ...
+
+    # number of randomly chosen counters decremented by each add
+    @property
+    def decrements(self) -> int: ...
+
+    # value that add sets an item's counters to
+    @property
+    def max_value(self) -> int: ...
+
+    # retrieve the hash_func given to __init__
+    @property
+    def hash_func(self) -> Callable[[Any], Union[int, bytes]]: ...
+
+    def add(self, obj: Any, /) -> None: ...
+
+    def __contains__(self, obj: Any) -> bool: ...
+
+    def __bool__(self) -> bool: ...                   # False if empty
+
+    def __repr__(self) -> str: ...                    # basic info
+
+    def clear(self) -> None: ...                      # remove all items
+
+    def copy(self) -> StableBloom: ...                # duplicate self
+
+
+@final
+class CountMinBloom:
+
+    # same arguments as Bloom, see section "Count-min filters"
+    def __init__(self, expected_items: int, false_positive_rate: float,
+                 hash_func=__builtins__.hash) -> None: ...
+
+    # number of counters in the filter
+    @property
+    def num_counters(self) -> int: ...
+
+    # number of rows of counters, one per hash function
+    @property
+    def rows(self) -> int: ...
+
+    # retrieve the hash_func given to __init__
+    @property
+    def hash_func(self) -> Callable[[Any], Union[int, bytes]]: ...
+
+    def add(self, obj: Any, /, count: int = 1) -> None: ...
+
+    # estimate of how often obj was added, never too low
+    def count(self, obj: Any, /) -> int: ...
+
+    def __contains__(self, obj: Any) -> bool: ...
+
+    def __bool__(self) -> bool: ...                   # False if empty
+
+    def __repr__(self) -> str: ...                    # basic info
+
+    def clear(self) -> None: ...                      # remove all items
+
+    def copy(self) -> CountMinBloom: ...              # duplicate self
@@ -1,13 +1,38 @@
 use bitline::BitLine;
-use pyo3::exceptions::{PyTypeError, PyValueError};
+use counterline::CounterLine;
+use pyo3::exceptions::{
+    PyException, PyIndexError, PyRuntimeError, PyRuntimeWarning, PyTypeError, PyUnicodeError,
+    PyUserWarning, PyValueError,
+};
 use pyo3::prelude::*;
 use pyo3::sync::GILOnceCell;
-use pyo3::types::PyType;
+use pyo3::types::{PyByteArray, PyDict, PyMemoryView, PyString, PyType};
 use pyo3::{basic::CompareOp, types::PyBytes, types::PyTuple, PyTraverseError, PyVisit};
-use std::fs::File;
-use std::io::{Read, Write};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
 use std::mem;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+/// Smallest filter that __init__ will create (one byte of the backing store)
+const MIN_SIZE_IN_BITS: u64 = 8;
+
+/// Number of buckets over which test_hash_quality computes chi-squared
+const QUALITY_BUCKETS: u64 = 256;
+
+/// Number of bytes of the filter that fingerprint hashes at a time
+const FINGERPRINT_CHUNK_SIZE: usize = 1 << 20;
+
+/// Number of bytes of the filter that save_to writes at a time
+const WRITE_CHUNK_SIZE: usize = 1 << 20;
+
+/// Factor by which the false positive rate has to exceed the optimal one
+/// for retune to warn
+const RETUNE_TOLERANCE: f64 = 1.1;
+
+/// Factor by which the false positive rate of a filter created with
+/// exact=True may fall short of the requested one
+const EXACT_TOLERANCE: f64 = 0.99;
 
 #[pyclass(module = "rbloom")]
 #[derive(Clone)]
@@ -16,50 +41,123 @@ struct Bloom {
     k: u64, // Number of hash functions (implemented via a LCG that uses
     // the original hash as a seed)
     hash_func: Option<Py<PyAny>>,
+    mode: IndexMode,
+    // The capacity and false positive rate the filter was designed for
+    expected_items: u64,
+    false_positive_rate: f64,
+    fill_warning: Option<FillWarning>,
+    /// Names the hash function, so that filters can be combined if their
+    /// hash_funcs are different objects that compute the same hashes
+    hash_id: Option<String>,
+    on_full: OnFull,
+    /// Keys that __contains__ reports as absent whatever their bits say,
+    /// grouped by their hash and compared with ==
+    exclusions: HashMap<ItemHash, Vec<Py<PyAny>>>,
+    journal: Journal,
 }
 
 #[pymethods]
 impl Bloom {
     #[new]
+    #[pyo3(signature = (expected_items, false_positive_rate, hash_func=None, *, blocked=false, warn_at_fill=None, hash_id=None, on_full="ignore", exact=false, journal_path=None))]
+    #[allow(clippy::too_many_arguments)]
     fn new(
         expected_items: u64,
         false_positive_rate: f64,
         hash_func: Option<Bound<'_, PyAny>>,
+        blocked: bool,
+        warn_at_fill: Option<f64>,
+        hash_id: Option<String>,
+        on_full: &str,
+        exact: bool,
+        journal_path: Option<PathBuf>,
     ) -> PyResult<Self> {
-        // Check the inputs
-        if false_positive_rate <= 0.0 || false_positive_rate >= 1.0 {
+        let (size_in_bits, k) = filter_parameters(expected_items, false_positive_rate)?;
+        let fill_warning = match warn_at_fill {
+            Some(threshold) if !(threshold > 0.0 && threshold <= 1.0) => {
+                return Err(PyValueError::new_err(
+                    "warn_at_fill must be greater than 0 and at most 1",
+                ))
+            }
+            Some(threshold) => Some(FillWarning {
+                threshold,
+                warned: false,
+            }),
+            None => None,
+        };
+        let mode = if blocked {
+            IndexMode::Blocked
+        } else {
+            IndexMode::Standard
+        };
+        let size_in_bits = mode.round_size(size_in_bits);
+        // tiny filters get more bits than the rate calls for, and so do
+        // small blocked ones, which are rounded up to whole blocks
+        let realized_rate =
+            self::false_positive_rate(size_in_bits as f64, expected_items as f64, k);
+        if exact && realized_rate < false_positive_rate * EXACT_TOLERANCE {
+            return Err(PyValueError::new_err(format!(
+                "a filter for {} items can't be made with a false positive rate of exactly \
+                 {}, the closest is {:.3e}",
+                expected_items, false_positive_rate, realized_rate
+            )));
+        }
+        let hash_func = custom_hash_func(hash_func)?;
+        if hash_id.is_some() && hash_func.is_none() {
             return Err(PyValueError::new_err(
-                "false_positive_rate must be between 0 and 1",
+                "hash_id can only be given along with a custom hash_func",
             ));
         }
-        if expected_items == 0 {
+        if journal_path.is_some() && hash_func.is_none() {
             return Err(PyValueError::new_err(
-                "expected_items must be greater than 0",
+                "journal_path can only be given along with a custom hash_func",
             ));
         }
-        let hash_func = match hash_func {
-            Some(hash_func) if !hash_func.is(builtin_hash_func(hash_func.py())?) => {
-                if !hash_func.is_callable() {
-                    return Err(PyTypeError::new_err("hash_func must be callable"));
-                }
-                Some(hash_func.unbind())
-            }
-            _ => None,
-        };
-
-        // Calculate the parameters for the filter
-        let size_in_bits =
-            -1.0 * (expected_items as f64) * false_positive_rate.ln() / 2.0f64.ln().powi(2);
-        let k = (size_in_bits / expected_items as f64) * 2.0f64.ln();
-
-        // Create the filter
+        if hash_id
+            .as_ref()
+            .is_some_and(|id| id.len() > u16::MAX as usize)
+        {
+            return Err(PyValueError::new_err(format!(
+                "hash_id must be at most {} bytes long",
+                u16::MAX
+            )));
+        }
         Ok(Bloom {
-            filter: BitLine::new(size_in_bits as u64)?,
-            k: k as u64,
+            filter: BitLine::new(size_in_bits)?,
+            k,
             hash_func,
+            mode,
+            expected_items,
+            false_positive_rate,
+            fill_warning,
+            hash_id,
+            on_full: OnFull::parse(on_full)?,
+            exclusions: HashMap::new(),
+            journal: match journal_path {
+                Some(path) => Journal::open(&path)?,
+                None => Journal::default(),
+            },
         })
     }
 
+    /// Number of items the filter was designed for
+    #[getter]
+    fn expected_items(&self) -> u64 {
+        self.expected_items
+    }
+
+    /// False positive rate the filter was designed for, which shrink updates
+    #[getter]
+    fn false_positive_rate(&self) -> f64 {
+        self.false_positive_rate
+    }
+
+    /// Whether all bits of an item lie within a single cache-line-sized block
+    #[getter]
+    fn blocked(&self) -> bool {
+        self.mode == IndexMode::Blocked
+    }
+
     /// Number of buckets in the filter
     #[getter]
     fn size_in_bits(&self) -> u64 {
@@ -75,21 +173,173 @@ impl Bloom {
         }
     }
 
-    /// Estimated number of items in the filter
+    /// Name of the hash function given to __init__ or loaded from a file
+    #[getter]
+    fn hash_id(&self) -> Option<&str> {
+        self.hash_id.as_deref()
+    }
+
+    /// What happens to items added once the filter is full, as given to
+    /// __init__
+    #[getter]
+    fn on_full(&self) -> &'static str {
+        self.on_full.name()
+    }
+
+    /// Estimated number of items in the filter. The estimate is accurate
+    /// up to a few times the expected number of items, and once (almost)
+    /// every bit is set it levels off instead of growing without bound.
     #[getter]
     fn approx_items(&self) -> f64 {
-        let len = self.filter.len() as f64;
-        let bits_set = self.filter.sum() as f64;
-        (len / (self.k as f64) * (1.0 - (bits_set) / len).ln()).abs()
+        match self.mode {
+            IndexMode::Standard => estimate_items(self.filter.sum(), self.filter.len(), self.k),
+            // the items aren't spread evenly over the blocks, and a block
+            // fills up faster than its share of the whole filter would
+            IndexMode::Blocked => self
+                .filter
+                .block_sums(lcg::BLOCK_BITS)
+                .map(|bits_set| estimate_items(bits_set, lcg::BLOCK_BITS, self.k))
+                .sum(),
+        }
     }
 
     #[pyo3(signature = (o, /))]
     fn add(&mut self, o: &Bound<'_, PyAny>) -> PyResult<()> {
         let hash = hash(o, &self.hash_func)?;
-        for index in lcg::generate_indexes(hash, self.k, self.filter.len()) {
-            self.filter.set(index);
+        self.make_room(o.py())?;
+        self.insert(hash);
+        self.journal.record(hash)?;
+        self.journal.flush()?;
+        self.check_fill(o.py())
+    }
+
+    /// Add each argument as an item, unlike update, which adds the items of
+    /// each argument
+    #[pyo3(signature = (*items))]
+    fn add_all(&mut self, items: &Bound<'_, PyTuple>) -> PyResult<()> {
+        for item in items.iter() {
+            let hash = hash(&item, &self.hash_func)?;
+            self.make_room(items.py())?;
+            self.insert(hash);
+            self.journal.record(hash)?;
+        }
+        self.journal.flush()?;
+        self.check_fill(items.py())
+    }
+
+    /// Add an item by its precomputed hash, i.e. the value of hash_func(o)
+    #[pyo3(signature = (h, /))]
+    fn add_hash(&mut self, h: &Bound<'_, PyAny>) -> PyResult<()> {
+        let hash = self.precomputed_hash(h)?;
+        self.make_room(h.py())?;
+        self.insert(hash);
+        self.journal.record(hash)?;
+        self.journal.flush()?;
+        self.check_fill(h.py())
+    }
+
+    /// Check for an item by its precomputed hash, i.e. the value of hash_func(o)
+    #[pyo3(signature = (h, /))]
+    fn contains_hash(&self, h: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let hash = self.precomputed_hash(h)?;
+        Ok(self.contains(hash))
+    }
+
+    /// Add every item of an iterable, optionally counting the items whose
+    /// bits were all already set (duplicates or false positives)
+    #[pyo3(signature = (items, /, count_collisions=false, *, progress=None, progress_every=100_000))]
+    fn add_batch(
+        &mut self,
+        items: &Bound<'_, PyAny>,
+        count_collisions: bool,
+        progress: Option<Bound<'_, PyAny>>,
+        progress_every: u64,
+    ) -> PyResult<Option<u64>> {
+        let mut progress = Progress::new(progress, progress_every)?;
+        let mut collisions = 0;
+        for obj in items.iter()? {
+            let hash = hash(&obj?, &self.hash_func)?;
+            self.make_room(items.py())?;
+            if count_collisions {
+                if self.insert_checked(hash) {
+                    collisions += 1;
+                }
+            } else {
+                self.insert(hash);
+            }
+            self.journal.record(hash)?;
+            progress.advance(1)?;
+        }
+        self.journal.flush()?;
+        progress.finish()?;
+        self.check_fill(items.py())?;
+        Ok(count_collisions.then_some(collisions))
+    }
+
+    /// Add each line of a text file as a str, without its line ending. The
+    /// file is read and decoded here, but every line still needs to be
+    /// turned into a str for hashing, whichever the hash function.
+    #[pyo3(signature = (filepath, encoding="utf-8", *, skip_empty=false, progress=None, progress_every=100_000))]
+    fn add_lines(
+        &mut self,
+        py: Python<'_>,
+        filepath: PathBuf,
+        encoding: &str,
+        skip_empty: bool,
+        progress: Option<Bound<'_, PyAny>>,
+        progress_every: u64,
+    ) -> PyResult<()> {
+        let is_utf8 = matches!(encoding.to_ascii_lowercase().as_str(), "utf-8" | "utf8");
+        // lines are split on b'\n' before decoding, which only works if
+        // the encoding leaves ASCII as it is
+        if !is_utf8 {
+            let ascii: Vec<u8> = (0..128).collect();
+            let encoded = PyString::new_bound(py, std::str::from_utf8(&ascii).unwrap())
+                .call_method1("encode", (encoding,));
+            let is_ascii_compatible = match encoded {
+                Ok(encoded) => encoded.downcast::<PyBytes>()?.as_bytes() == ascii,
+                // e.g. an unknown encoding, which raises a LookupError
+                Err(err) if !err.is_instance_of::<PyUnicodeError>(py) => return Err(err),
+                Err(_) => false,
+            };
+            if !is_ascii_compatible {
+                return Err(PyValueError::new_err(format!(
+                    "add_lines only supports encodings that are compatible with ASCII, \
+                     which {:?} isn't",
+                    encoding
+                )));
+            }
+        }
+        let mut progress = Progress::new(progress, progress_every)?;
+        let mut file = BufReader::with_capacity(1 << 16, File::open(filepath)?);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if file.read_until(b'\n', &mut line)? == 0 {
+                self.journal.flush()?;
+                progress.finish()?;
+                return self.check_fill(py);
+            }
+            if line.ends_with(b"\n") {
+                line.pop();
+                if line.ends_with(b"\r") {
+                    line.pop();
+                }
+            }
+            if skip_empty && line.is_empty() {
+                continue;
+            }
+            let item = match std::str::from_utf8(&line) {
+                Ok(text) if is_utf8 => PyString::new_bound(py, text).into_any(),
+                // let Python do the decoding, and raise its usual errors
+                _ => PyBytes::new_bound(py, &line).call_method1("decode", (encoding,))?,
+            };
+            let hash = hash(&item, &self.hash_func)?;
+            self.make_room(py)?;
+            self.insert(hash);
+            self.journal.record(hash)?;
+            progress.advance(1)?;
         }
-        Ok(())
     }
 
     /// Test whether every element in the bloom may be in other
@@ -118,19 +368,88 @@ impl Bloom {
 
     fn __contains__(&self, o: &Bound<'_, PyAny>) -> PyResult<bool> {
         let hash = hash(o, &self.hash_func)?;
-        for index in lcg::generate_indexes(hash, self.k, self.filter.len()) {
-            if !self.filter.get(index) {
-                return Ok(false);
+        Ok(self.contains(hash) && !self.is_excluded(o, hash)?)
+    }
+
+    /// Make __contains__ return False for o from now on, even if its bits
+    /// are set. Only valid for keys that were never added, or the filter
+    /// gets false negatives.
+    #[pyo3(signature = (o, /))]
+    fn add_exclusion(&mut self, o: &Bound<'_, PyAny>) -> PyResult<()> {
+        let hash = hash(o, &self.hash_func)?;
+        let keys = self.exclusions.entry(hash).or_default();
+        for key in keys.iter() {
+            if key.bind(o.py()).eq(o)? {
+                return Ok(());
+            }
+        }
+        keys.push(o.clone().unbind());
+        Ok(())
+    }
+
+    /// Fraction of the items that are (probably) in the filter, counted in
+    /// a single pass over them without keeping their hashes around
+    #[pyo3(signature = (items, /))]
+    fn contains_fraction(&self, items: &Bound<'_, PyAny>) -> PyResult<f64> {
+        let (mut total, mut present) = (0u64, 0u64);
+        for o in items.iter()? {
+            let o = o?;
+            let hash = hash(&o, &self.hash_func)?;
+            total += 1;
+            if self.contains(hash) && !self.is_excluded(&o, hash)? {
+                present += 1;
             }
         }
-        Ok(true)
+        if total == 0 {
+            return Err(PyValueError::new_err("items must not be empty"));
+        }
+        Ok(present as f64 / total as f64)
+    }
+
+    /// Fraction of items that were never added that the filter claims to
+    /// contain, i.e. contains_fraction under another name for when the
+    /// items are known to be absent
+    #[pyo3(signature = (known_absent_items, /))]
+    fn measured_false_positive_rate(&self, known_absent_items: &Bound<'_, PyAny>) -> PyResult<f64> {
+        self.contains_fraction(known_absent_items)
+    }
+
+    /// Value of an individual bit of the filter
+    fn __getitem__(&self, index: i64) -> PyResult<bool> {
+        match u64::try_from(index) {
+            Ok(index) if index < self.filter.len() => Ok(self.filter.get(index)),
+            _ => Err(PyIndexError::new_err("bit index out of range")),
+        }
+    }
+
+    /// Defined only so that __getitem__ doesn't make the filter iterable
+    /// over its bits, which would look like iterating over its items
+    fn __iter__(&self) -> PyResult<PyObject> {
+        Err(PyTypeError::new_err(
+            "'Bloom' object is not iterable, it doesn't store its items",
+        ))
+    }
+
+    /// Indices of the k bits that adding o would set, in the order they
+    /// are generated (which may repeat an index), without touching the
+    /// filter
+    #[pyo3(signature = (o, /))]
+    fn indices_for(&self, o: &Bound<'_, PyAny>) -> PyResult<Vec<u64>> {
+        let hash = hash(o, &self.hash_func)?;
+        let mut indices = Vec::with_capacity(self.k as usize);
+        hash.for_each_index(self.mode, self.k, self.filter.len(), |index| {
+            indices.push(index)
+        });
+        Ok(indices)
     }
 
     /// Return a new set with elements from the set and all others.
     #[pyo3(signature = (*others))]
     fn union(&self, others: &Bound<'_, PyTuple>) -> PyResult<Self> {
         let mut result = self.clone();
-        result.update(others)?;
+        for other in others.iter() {
+            result.union_with(&other)?;
+        }
         Ok(result)
     }
 
@@ -138,89 +457,196 @@ impl Bloom {
     #[pyo3(signature = (*others))]
     fn intersection(&self, others: &Bound<'_, PyTuple>) -> PyResult<Self> {
         let mut result = self.clone();
-        result.intersection_update(others)?;
+        // Lazily allocated temp bitset
+        let mut temp: Option<Self> = None;
+        for other in others.iter() {
+            result.intersect_with(&other, &mut temp)?;
+        }
         Ok(result)
     }
 
-    fn __or__(&self, py: Python<'_>, other: &Bloom) -> PyResult<Bloom> {
-        check_compatible(self, other)?;
-        Ok(Bloom {
-            filter: &self.filter | &other.filter,
-            k: self.k,
-            hash_func: self.hash_fn_clone(py),
-        })
+    fn __or__(&self, other: &Bound<'_, PyAny>) -> PyResult<Bloom> {
+        let mut result = self.clone();
+        result.union_with(other)?;
+        Ok(result)
     }
 
-    fn __ior__(&mut self, other: &Bloom) -> PyResult<()> {
-        check_compatible(self, other)?;
-        self.filter |= &other.filter;
+    fn __ior__(mut slf: PyRefMut<'_, Self>, other: &Bound<'_, PyAny>) -> PyResult<()> {
+        // b |= b is a no-op, and borrowing b again would fail
+        if slf.as_ptr() != other.as_ptr() {
+            slf.union_with(other)?;
+        }
         Ok(())
     }
 
-    fn __and__(&self, py: Python<'_>, other: &Bloom) -> PyResult<Bloom> {
-        check_compatible(self, other)?;
-        Ok(Bloom {
-            filter: &self.filter & &other.filter,
-            k: self.k,
-            hash_func: self.hash_fn_clone(py),
-        })
+    fn __and__(&self, other: &Bound<'_, PyAny>) -> PyResult<Bloom> {
+        let mut result = self.clone();
+        result.intersect_with(other, &mut None)?;
+        Ok(result)
     }
 
-    fn __iand__(&mut self, other: &Bloom) -> PyResult<()> {
-        check_compatible(self, other)?;
-        self.filter &= &other.filter;
+    fn __iand__(mut slf: PyRefMut<'_, Self>, other: &Bound<'_, PyAny>) -> PyResult<()> {
+        // b &= b is a no-op, and borrowing b again would fail
+        if slf.as_ptr() != other.as_ptr() {
+            slf.intersect_with(other, &mut None)?;
+        }
         Ok(())
     }
 
     #[pyo3(signature = (*others))]
-    fn update(&mut self, others: &Bound<'_, PyTuple>) -> PyResult<()> {
+    fn update(mut slf: PyRefMut<'_, Self>, others: &Bound<'_, PyTuple>) -> PyResult<()> {
         for other in others.iter() {
-            // If the other object is a Bloom, use the bitwise union
-            if let Ok(other) = other.downcast::<Bloom>() {
-                let other = other.try_borrow()?;
-                self.__ior__(&other)?;
-            }
-            // Otherwise, iterate over the other object and add each item
-            else {
-                for obj in other.iter()? {
-                    self.add(&obj?)?;
-                }
+            if slf.as_ptr() != other.as_ptr() {
+                slf.union_with(&other)?;
             }
         }
         Ok(())
     }
 
     #[pyo3(signature = (*others))]
-    fn intersection_update(&mut self, others: &Bound<'_, PyTuple>) -> PyResult<()> {
+    fn intersection_update(
+        mut slf: PyRefMut<'_, Self>,
+        others: &Bound<'_, PyTuple>,
+    ) -> PyResult<()> {
         // Lazily allocated temp bitset
         let mut temp: Option<Self> = None;
         for other in others.iter() {
-            // If the other object is a Bloom, use the bitwise intersection
-            if let Ok(other) = other.downcast::<Bloom>() {
-                let other = other.try_borrow()?;
-                self.__iand__(&other)?;
-            }
-            // Otherwise, iterate over the other object and add each item
-            else {
-                let temp = temp.get_or_insert_with(|| self.clone());
-                temp.clear();
-                for obj in other.iter()? {
-                    temp.add(&obj?)?;
-                }
-                self.__iand__(temp)?;
+            if slf.as_ptr() != other.as_ptr() {
+                slf.intersect_with(&other, &mut temp)?;
             }
         }
         Ok(())
     }
 
-    fn clear(&mut self) {
+    /// Always raises, as items can't be removed from a plain Bloom filter
+    #[pyo3(signature = (*_others))]
+    fn difference_update(&self, _others: &Bound<'_, PyTuple>) -> PyResult<()> {
+        Err(PyTypeError::new_err(
+            "cannot remove from a non-counting bloom filter, use CountingBloom instead",
+        ))
+    }
+
+    /// Empty the filter and give it the size and number of hash functions
+    /// of Bloom(expected_items, false_positive_rate), keeping its hash_func
+    /// and whether it is blocked. The old allocation is reused if it is
+    /// large enough.
+    #[pyo3(signature = (expected_items, false_positive_rate, /))]
+    fn reconfigure(&mut self, expected_items: u64, false_positive_rate: f64) -> PyResult<()> {
+        let (size_in_bits, k) = filter_parameters(expected_items, false_positive_rate)?;
+        self.check_journal("reconfigure")?;
+        self.filter.reset(self.mode.round_size(size_in_bits))?;
+        self.exclusions.clear();
+        self.reset_fill_warning();
+        self.k = k;
+        self.expected_items = expected_items;
+        self.false_positive_rate = false_positive_rate;
+        self.checkpoint_journal()
+    }
+
+    /// Fold the filter to 1/factor of its size by ORing its factor segments
+    /// together. Every item in the filter stays in it, but the false
+    /// positive rate goes up, and k stays as it was.
+    #[pyo3(signature = (factor, /))]
+    fn shrink(&mut self, factor: u64) -> PyResult<()> {
+        let granularity = self.mode.fold_granularity();
+        if factor == 0 || !self.filter.len().is_multiple_of(granularity * factor) {
+            return Err(PyValueError::new_err(format!(
+                "size_in_bits must be divisible by {} times the factor",
+                granularity
+            )));
+        }
+        self.check_journal("shrink")?;
+        self.filter = self.filter.fold(factor);
+        self.false_positive_rate =
+            false_positive_rate(self.filter.len() as f64, self.expected_items as f64, self.k);
+        self.checkpoint_journal()
+    }
+
+    /// Union with a filter that differs from this one only in size. The
+    /// result has the largest size that both can be shrunk to, i.e. the
+    /// smaller size if it divides the larger one, and both are folded to
+    /// it as shrink would, so k stays the same but the false positive rate
+    /// is that of the smaller filter.
+    #[pyo3(signature = (other, /))]
+    fn merge(&self, other: &Bound<'_, PyAny>) -> PyResult<Bloom> {
+        let other = other
+            .downcast::<Bloom>()
+            .map_err(|_| PyTypeError::new_err("other must be a Bloom"))?
+            .try_borrow()?;
+        if self.k != other.k {
+            return Err(PyValueError::new_err(format!(
+                "only filters with the same number of hash functions can be merged, but they \
+                 have {} and {}",
+                self.k, other.k
+            )));
+        }
+        let (len, other_len) = (self.filter.len(), other.filter.len());
+        let size_in_bits = gcd(len, other_len);
+        if !size_in_bits.is_multiple_of(self.mode.fold_granularity()) {
+            return Err(PyValueError::new_err(format!(
+                "filters of {} and {} bits can't be folded to a common size, which would \
+                 have to be a multiple of {}",
+                len,
+                other_len,
+                self.mode.fold_granularity()
+            )));
+        }
+        check_same_hashing(self, &other)?;
+        let mut result = self.clone();
+        result.shrink(len / size_in_bits)?;
+        let (filter, other_filter) = (&mut result.filter, &other.filter);
+        other
+            .py()
+            .allow_threads(|| *filter |= &other_filter.fold(other_len / size_in_bits));
+        Ok(result)
+    }
+
+    fn clear(&mut self) -> PyResult<()> {
+        self.check_journal("clear")?;
         self.filter.clear();
+        self.exclusions.clear();
+        self.reset_fill_warning();
+        self.checkpoint_journal()
     }
 
     fn copy(&self) -> Bloom {
         self.clone()
     }
 
+    /// Return a new, empty filter with all the parameters of other, including
+    /// its hash_func, so that the two can always be combined
+    #[classmethod]
+    #[pyo3(signature = (other, /))]
+    fn empty_like(_cls: &Bound<'_, PyType>, other: &Bound<'_, PyAny>) -> PyResult<Bloom> {
+        let other = other
+            .downcast::<Bloom>()
+            .map_err(|_| PyTypeError::new_err("other must be a Bloom"))?
+            .try_borrow()?;
+        Ok(other.empty_copy(other.py()))
+    }
+
+    /// Return a new, empty filter with all the parameters of template,
+    /// including its hash_func, to which the keys are added. The keys should
+    /// be the items that were added to this filter, whose bits can't be
+    /// moved to a filter with other parameters; this filter is left as it is.
+    #[pyo3(signature = (template, keys, /))]
+    fn rebuild_compatible_with(
+        &self,
+        template: &Bound<'_, PyAny>,
+        keys: &Bound<'_, PyAny>,
+    ) -> PyResult<Bloom> {
+        let template = template
+            .downcast::<Bloom>()
+            .map_err(|_| PyTypeError::new_err("template must be a Bloom"))?
+            .try_borrow()?;
+        let mut result = template.empty_copy(keys.py());
+        drop(template);
+        for key in keys.iter()? {
+            result.add(&key?)?;
+        }
+        Ok(result)
+    }
+
     fn __repr__(&self) -> String {
         // Use a format that makes it clear that the object
         // cannot be reconstructed from the repr
@@ -235,6 +661,35 @@ impl Bloom {
         !self.filter.is_empty()
     }
 
+    /// Summary of the filter for Jupyter, with the fraction of bits set
+    /// drawn as a bar
+    fn _repr_html_(&self) -> String {
+        let fill = self.filter.sum() as f64 / self.filter.len() as f64;
+        format!(
+            "<table>\
+             <tr><th colspan=\"2\" style=\"text-align: left\">Bloom</th></tr>\
+             <tr><td>size</td><td>{}</td></tr>\
+             <tr><td>approx_items</td><td>{:.1} of {} expected</td></tr>\
+             <tr><td>bits set</td><td>\
+             <div style=\"display: inline-block; vertical-align: middle; width: 100px; \
+             height: 0.8em; border: 1px solid gray\">\
+             <div style=\"width: {:.1}%; height: 100%; background: {}\"></div></div> \
+             {:.1}%</td></tr>\
+             <tr><td>hash functions</td><td>{}</td></tr>\
+             <tr><td>false_positive_rate</td><td>{}</td></tr>\
+             </table>",
+            human_size(self.filter.bits().len() as u64),
+            self.approx_items(),
+            self.expected_items,
+            fill * 100.0,
+            // half the bits are set at the expected number of items
+            if fill > 0.5 { "orange" } else { "steelblue" },
+            fill * 100.0,
+            self.k,
+            self.false_positive_rate,
+        )
+    }
+
     fn __richcmp__(&self, other: &Self, op: CompareOp) -> PyResult<bool> {
         check_compatible(self, other)?;
         Ok(match op {
@@ -250,122 +705,1107 @@ impl Bloom {
     #[classattr]
     const __hash__: Option<Py<PyAny>> = None;
 
-    /// Load from a file, see "Persistence" section in the README
-    #[classmethod]
-    fn load(
-        _cls: &Bound<'_, PyType>,
-        filepath: PathBuf,
-        hash_func: &Bound<'_, PyAny>,
-    ) -> PyResult<Bloom> {
-        // check that the hash_func is callable
-        if !hash_func.is_callable() {
-            return Err(PyTypeError::new_err("hash_func must be callable"));
+    /// Whether other has the same k, is blocked the same way and has the
+    /// same bits, whatever its hash function. Unlike ==, this doesn't
+    /// raise for filters whose hash_funcs are different objects.
+    #[pyo3(signature = (other, /))]
+    fn structurally_equal(&self, other: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let other = other
+            .downcast::<Bloom>()
+            .map_err(|_| PyTypeError::new_err("other must be a Bloom"))?
+            .try_borrow()?;
+        Ok(self.k == other.k && self.mode == other.mode && self.filter == other.filter)
+    }
+
+    /// Number of hash functions that would minimize the false positive rate
+    /// at the current size and approx_items. For an empty filter, which
+    /// doesn't have a false positive rate, that's the current number.
+    fn optimal_num_hashes(&self) -> u64 {
+        let items = self.approx_items();
+        if items == 0.0 {
+            return self.k;
         }
-        // check that the hash_func isn't the built-in hash function
-        if hash_func.is(builtin_hash_func(hash_func.py())?) {
-            return Err(PyValueError::new_err(
-                "Cannot load a bloom filter that uses the built-in hash function",
-            ));
+        // the false positive rate is lowest at one of the integers around
+        // the continuous optimum
+        let size_in_bits = self.filter.len() as f64;
+        let k = optimal_k(size_in_bits, items);
+        let (lower, upper) = ((k.floor() as u64).max(1), (k.ceil() as u64).max(1));
+        if false_positive_rate(size_in_bits, items, lower)
+            <= false_positive_rate(size_in_bits, items, upper)
+        {
+            lower
+        } else {
+            upper
         }
-        let hash_func = Some(hash_func.to_object(hash_func.py()));
-
-        let mut file = File::open(filepath)?;
+    }
 
-        let mut k_bytes = [0; mem::size_of::<u64>()];
-        file.read_exact(&mut k_bytes)?;
-        let k = u64::from_le_bytes(k_bytes);
+    /// The numbers describing how full the filter is, computed together:
+    /// size_in_bits, k, bits_set, fill_ratio, expected_bits_set (at
+    /// expected_items, for a uniform hash), approx_items,
+    /// current_false_positive_rate (going by the bits set) and the design
+    /// false_positive_rate
+    fn stats<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let len = self.filter.len();
+        let bits_set = self.filter.sum();
+        let (approx_items, current_false_positive_rate) = match self.mode {
+            IndexMode::Standard => (
+                estimate_items(bits_set, len, self.k),
+                (bits_set as f64 / len as f64).powi(self.k as i32),
+            ),
+            // an item's bits are all in one block, so the rate is the
+            // average over the blocks
+            IndexMode::Blocked => {
+                let (items, rate) = self.filter.block_sums(lcg::BLOCK_BITS).fold(
+                    (0.0, 0.0),
+                    |(items, rate), block_bits_set| {
+                        (
+                            items + estimate_items(block_bits_set, lcg::BLOCK_BITS, self.k),
+                            rate + (block_bits_set as f64 / lcg::BLOCK_BITS as f64)
+                                .powi(self.k as i32),
+                        )
+                    },
+                );
+                (items, rate / (len / lcg::BLOCK_BITS) as f64)
+            }
+        };
+        let insertions = self.k as f64 * self.expected_items as f64;
+
+        let stats = PyDict::new_bound(py);
+        stats.set_item("size_in_bits", len)?;
+        stats.set_item("k", self.k)?;
+        stats.set_item("bits_set", bits_set)?;
+        stats.set_item("fill_ratio", bits_set as f64 / len as f64)?;
+        stats.set_item(
+            "expected_bits_set",
+            len as f64 * -(insertions * (-1.0 / len as f64).ln_1p()).exp_m1(),
+        )?;
+        stats.set_item("approx_items", approx_items)?;
+        stats.set_item("current_false_positive_rate", current_false_positive_rate)?;
+        stats.set_item("false_positive_rate", self.false_positive_rate)?;
+        Ok(stats)
+    }
 
-        let filter = BitLine::load(&mut file)?;
+    /// Number of additional distinct items that can be added before the
+    /// false positive rate rises above false_positive_rate, going by
+    /// approx_items, or 0 if it already has
+    fn remaining_capacity(&self) -> u64 {
+        let capacity =
+            max_items_for_rate(self.filter.len() as f64, self.k, self.false_positive_rate);
+        (capacity - self.approx_items()).max(0.0) as u64
+    }
 
-        Ok(Bloom {
-            filter,
-            k,
-            hash_func,
-        })
+    /// Warn if the filter holds so many items for its size that its number
+    /// of hash functions makes the false positive rate noticeably worse than
+    /// optimal_num_hashes would. The number can't be changed without
+    /// rebuilding the filter. Filters holding fewer items than they were
+    /// made for would do even better with more hash functions, but they
+    /// already beat their design rate, so they don't get a warning.
+    fn retune(&self, py: Python<'_>) -> PyResult<()> {
+        let optimal = self.optimal_num_hashes();
+        let items = self.approx_items();
+        let size_in_bits = self.filter.len() as f64;
+        let rate = false_positive_rate(size_in_bits, items, self.k);
+        let optimal_rate = false_positive_rate(size_in_bits, items, optimal);
+        if optimal < self.k && rate > optimal_rate * RETUNE_TOLERANCE {
+            let message = format!(
+                "the filter uses {} hash functions, giving a false positive rate of {:.3e} \
+                 at about {:.0} items, while {} would give {:.3e}; rebuild the filter \
+                 to change it",
+                self.k, rate, items, optimal, optimal_rate
+            );
+            PyErr::warn_bound(py, &py.get_type_bound::<PyRuntimeWarning>(), &message, 1)?;
+        }
+        Ok(())
     }
 
-    /// Load from a bytes(), see "Persistence" section in the README
+    /// Best achievable false positive rate and corresponding number of hash
+    /// functions for a given memory budget, i.e. the rate and k of the
+    /// best filter __init__ can create in size_in_bits, without allocating
+    /// it
     #[classmethod]
-    fn load_bytes(
+    fn rate_for_memory(
         _cls: &Bound<'_, PyType>,
-        bytes: &[u8],
-        hash_func: &Bound<'_, PyAny>,
-    ) -> PyResult<Bloom> {
-        // check that the hash_func is callable
-        if !hash_func.is_callable() {
-            return Err(PyTypeError::new_err("hash_func must be callable"));
-        }
-        // check that the hash_func isn't the built-in hash function
-        if hash_func.is(builtin_hash_func(hash_func.py())?) {
+        expected_items: u64,
+        size_in_bits: u64,
+    ) -> PyResult<(f64, u64)> {
+        if expected_items == 0 {
             return Err(PyValueError::new_err(
-                "Cannot load a bloom filter that uses the built-in hash function",
+                "expected_items must be greater than 0",
             ));
         }
-        let hash_func = Some(hash_func.to_object(hash_func.py()));
-
-        let k_bytes: [u8; mem::size_of::<u64>()] = bytes[0..mem::size_of::<u64>()]
-            .try_into()
-            .expect("slice with incorrect length");
-        let k = u64::from_le_bytes(k_bytes);
+        if size_in_bits == 0 {
+            return Err(PyValueError::new_err("size_in_bits must be greater than 0"));
+        }
 
-        let filter = BitLine::load_bytes(&bytes[mem::size_of::<u64>()..])?;
+        // the size that __init__ would allocate, in whole bytes
+        let size_for = |false_positive_rate| -> PyResult<u64> {
+            let (size, _) = filter_parameters(expected_items, false_positive_rate)?;
+            Ok(size.div_ceil(8) * 8)
+        };
 
-        Ok(Bloom {
-            filter,
-            k,
-            hash_func,
-        })
+        // Start from the rate of the rounded k that __init__ would use,
+        // and then correct for the rounding of the size, which can push
+        // it over by a byte, by searching for the lowest rate that fits.
+        // Rates too small for a float are reported as the smallest one.
+        let too_small = || {
+            PyValueError::new_err(format!(
+                "size_in_bits is too small for {} items",
+                expected_items
+            ))
+        };
+        let items = expected_items as f64;
+        let k = (optimal_k(size_in_bits as f64, items).round() as u64).max(1);
+        let mut low = false_positive_rate(size_in_bits as f64, items, k).max(f64::MIN_POSITIVE);
+        if low >= 1.0 {
+            return Err(too_small());
+        }
+        if size_for(low)? > size_in_bits {
+            let mut high = low;
+            while size_for(high)? > size_in_bits {
+                low = high;
+                high = (high + 1.0) / 2.0;
+                if high >= 1.0 {
+                    return Err(too_small());
+                }
+            }
+            loop {
+                let middle = (low + high) / 2.0;
+                if middle <= low || middle >= high {
+                    break;
+                }
+                if size_for(middle)? > size_in_bits {
+                    low = middle;
+                } else {
+                    high = middle;
+                }
+            }
+            low = high;
+        }
+        let (_, k) = filter_parameters(expected_items, low)?;
+        Ok((low, k))
     }
 
-    /// Save to a file, see "Persistence" section in the README
-    fn save(&self, filepath: PathBuf) -> PyResult<()> {
-        if self.hash_func.is_none() {
-            return Err(PyValueError::new_err(
-                "Cannot save a bloom filter that uses the built-in hash function",
-            ));
+    /// Largest expected_items for which a filter with the given false
+    /// positive rate fits into size_in_bits, and its number of hash
+    /// functions, without allocating a filter
+    #[classmethod]
+    fn capacity_for(
+        _cls: &Bound<'_, PyType>,
+        size_in_bits: u64,
+        false_positive_rate: f64,
+    ) -> PyResult<(u64, u64)> {
+        // the size that __init__ would allocate, in whole bytes
+        let size_for = |expected_items| -> PyResult<u64> {
+            let (size, _) = filter_parameters(expected_items, false_positive_rate)?;
+            Ok(size.div_ceil(8) * 8)
+        };
+        // check the arguments
+        size_for(1)?;
+
+        // Invert the size formula used by __init__, and then correct for
+        // its rounding
+        let k = (-false_positive_rate.log2()).round().max(1.0) as u64;
+        let mut expected_items =
+            (size_in_bits as f64 * max_items_for_rate(1.0, k, false_positive_rate)) as u64;
+        while expected_items > 0 && size_for(expected_items)? > size_in_bits {
+            expected_items -= 1;
         }
-        let mut file = File::create(filepath)?;
-        file.write_all(&self.k.to_le_bytes())?;
-        self.filter.save(&mut file)?;
-        Ok(())
+        while size_for(expected_items + 1)? <= size_in_bits {
+            expected_items += 1;
+        }
+        if expected_items == 0 {
+            return Err(PyValueError::new_err(format!(
+                "size_in_bits must be at least {} for that false_positive_rate",
+                size_for(1)?
+            )));
+        }
+        let (_, k) = filter_parameters(expected_items, false_positive_rate)?;
+        Ok((expected_items, k))
     }
 
-    /// Save to a byte(), see "Persistence" section in the README
-    fn save_bytes<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
-        const K_SIZE: usize = mem::size_of::<u64>();
-        if self.hash_func.is_none() {
+    /// Create an empty filter with exactly the given size and number of
+    /// hash functions, e.g. to match a filter built elsewhere. Since it
+    /// wasn't sized for a capacity, expected_items and false_positive_rate
+    /// assume the one at which num_hashes is optimal.
+    #[classmethod]
+    #[pyo3(signature = (size_in_bits, num_hashes, hash_func=None))]
+    fn with_size(
+        _cls: &Bound<'_, PyType>,
+        size_in_bits: u64,
+        num_hashes: u64,
+        hash_func: Option<Bound<'_, PyAny>>,
+    ) -> PyResult<Bloom> {
+        if size_in_bits == 0 || !size_in_bits.is_multiple_of(8) {
             return Err(PyValueError::new_err(
-                "Cannot save a bloom filter that uses the built-in hash function",
+                "size_in_bits must be a positive multiple of 8, as filters are stored in \
+                 whole bytes",
             ));
         }
-
-        debug_assert_eq!(K_SIZE, self.k.to_le_bytes().len());
-        let len = K_SIZE + self.filter.bits().len();
-        PyBytes::new_bound_with(py, len, |data| {
-            data[..K_SIZE].copy_from_slice(&self.k.to_le_bytes());
-            data[K_SIZE..].copy_from_slice(self.filter.bits());
-            Ok(())
+        if num_hashes == 0 {
+            return Err(PyValueError::new_err("num_hashes must be at least 1"));
+        }
+        let (expected_items, false_positive_rate) = assumed_design(size_in_bits, num_hashes);
+        Ok(Bloom {
+            filter: BitLine::new(size_in_bits)?,
+            k: num_hashes,
+            hash_func: custom_hash_func(hash_func)?,
+            mode: IndexMode::Standard,
+            expected_items,
+            false_positive_rate,
+            fill_warning: None,
+            hash_id: None,
+            on_full: OnFull::Ignore,
+            exclusions: HashMap::new(),
+            journal: Journal::default(),
         })
     }
 
-    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
-        visit.call(&self.hash_func)?;
-        Ok(())
-    }
-}
-
-// Non-python methods
-impl Bloom {
-    fn hash_fn_clone(&self, py: Python<'_>) -> Option<Py<PyAny>> {
-        self.hash_func.as_ref().map(|f| f.clone_ref(py))
-    }
-
-    fn zeroed_clone(&self, py: Python<'_>) -> Bloom {
-        Bloom {
-            filter: BitLine::new(self.filter.len()).unwrap(),
-            k: self.k,
-            hash_func: self.hash_fn_clone(py),
+    /// Run sample keys through a scratch filter to sanity-check a hash_func
+    ///
+    /// The returned metrics compare the fill ratio with the one expected for
+    /// a uniform hash, give the chi-squared statistic of the generated indexes
+    /// over equally sized buckets of the filter, and report the fraction of
+    /// samples whose bits were all already set when they were inserted
+    #[classmethod]
+    #[pyo3(signature = (hash_func, samples, false_positive_rate=0.01))]
+    fn test_hash_quality<'py>(
+        _cls: &Bound<'py, PyType>,
+        hash_func: &Bound<'py, PyAny>,
+        samples: &Bound<'py, PyAny>,
+        false_positive_rate: f64,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let py = hash_func.py();
+        let samples = samples.iter()?.collect::<PyResult<Vec<_>>>()?;
+        if samples.is_empty() {
+            return Err(PyValueError::new_err("samples must not be empty"));
+        }
+        let mut scratch = Bloom::new(
+            samples.len() as u64,
+            false_positive_rate,
+            Some(hash_func.clone()),
+            false,
+            None,
+            None,
+            "ignore",
+            false,
+            None,
+        )?;
+        let len = scratch.filter.len();
+        let num_buckets = QUALITY_BUCKETS.min(len);
+
+        let mut bucket_counts = vec![0u64; num_buckets as usize];
+        let mut collisions = 0u64;
+        for sample in &samples {
+            let hash = hash(sample, &scratch.hash_func)?;
+            let mut all_set = true;
+            hash.for_each_index(scratch.mode, scratch.k, len, |index| {
+                let bucket = index as u128 * num_buckets as u128 / len as u128;
+                bucket_counts[bucket as usize] += 1;
+                all_set &= scratch.filter.get(index);
+            });
+            scratch.insert(hash);
+            if all_set {
+                collisions += 1;
+            }
         }
+
+        let n = samples.len() as f64;
+        let insertions = (scratch.k as f64) * n;
+        let expected_per_bucket = insertions / num_buckets as f64;
+        let chi_squared: f64 = bucket_counts
+            .iter()
+            .map(|&count| (count as f64 - expected_per_bucket).powi(2) / expected_per_bucket)
+            .sum();
+
+        let metrics = PyDict::new_bound(py);
+        metrics.set_item("fill_ratio", scratch.filter.sum() as f64 / len as f64)?;
+        metrics.set_item(
+            "expected_fill_ratio",
+            1.0 - (insertions * (-1.0 / len as f64).ln_1p()).exp(),
+        )?;
+        metrics.set_item("chi_squared", chi_squared)?;
+        metrics.set_item("degrees_of_freedom", num_buckets - 1)?;
+        metrics.set_item("collision_fraction", collisions as f64 / n)?;
+        Ok(metrics)
+    }
+
+    /// Build a filter from an array of precomputed 64-bit hashes, such as a
+    /// numpy int64 or uint64 array, without going through Python for each
+    /// hash. The hashes are used as add_hash would use them; hash_func is
+    /// only stored for later use. The GIL is released while the hashes are
+    /// added, and only taken again to call progress.
+    #[classmethod]
+    #[pyo3(signature = (hashes, expected_items, false_positive_rate, hash_func=None, *, blocked=false, hash_id=None, progress=None, progress_every=100_000))]
+    #[allow(clippy::too_many_arguments)]
+    fn from_hashes(
+        _cls: &Bound<'_, PyType>,
+        hashes: &Bound<'_, PyAny>,
+        expected_items: u64,
+        false_positive_rate: f64,
+        hash_func: Option<Bound<'_, PyAny>>,
+        blocked: bool,
+        hash_id: Option<String>,
+        progress: Option<Bound<'_, PyAny>>,
+        progress_every: u64,
+    ) -> PyResult<Bloom> {
+        let py = hashes.py();
+        let mut progress = Progress::new(progress, progress_every)?;
+        let view = PyMemoryView::from_bound(hashes)?;
+        let format: String = view.getattr("format")?.extract()?;
+        let itemsize: usize = view.getattr("itemsize")?.extract()?;
+        let signed = match (native_format(&format), itemsize) {
+            (Some('q' | 'l'), 8) => true,
+            (Some('Q' | 'L'), 8) => false,
+            _ => {
+                return Err(PyTypeError::new_err(format!(
+                    "hashes must be an array of 64-bit integers, got format '{}' with \
+                     {} bytes per item",
+                    format, itemsize
+                )))
+            }
+        };
+        // the limited API has no access to the buffer itself, so take a copy
+        let data = view.call_method0("tobytes")?;
+        let data = data.downcast::<PyBytes>()?.as_bytes();
+
+        let mut bloom = Bloom::new(
+            expected_items,
+            false_positive_rate,
+            hash_func,
+            blocked,
+            None,
+            hash_id,
+            "ignore",
+            false,
+            None,
+        )?;
+        // without a callback to call, all hashes are added in one go
+        let batch_len = match progress.callback {
+            Some(_) => progress_every
+                .saturating_mul(8)
+                .try_into()
+                .unwrap_or(usize::MAX),
+            None => data.len().max(1),
+        };
+        for batch in data.chunks(batch_len) {
+            py.allow_threads(|| bloom.insert_raw_hashes(batch, signed))?;
+            progress.advance(batch.len() as u64 / 8)?;
+        }
+        progress.finish()?;
+        Ok(bloom)
+    }
+
+    /// Load from a file, see "Persistence" section in the README
+    #[classmethod]
+    fn load(
+        _cls: &Bound<'_, PyType>,
+        filepath: PathBuf,
+        hash_func: &Bound<'_, PyAny>,
+    ) -> PyResult<Bloom> {
+        let mut file = File::open(filepath)?;
+        Bloom::read_complete(&mut file, hash_func, Checksum::IfPresent)
+    }
+
+    /// Load from a file object as written by save_to, reading it a piece at
+    /// a time, see "Persistence" section in the README
+    #[classmethod]
+    #[pyo3(signature = (fileobj, hash_func, verify=true))]
+    fn load_from(
+        _cls: &Bound<'_, PyType>,
+        fileobj: &Bound<'_, PyAny>,
+        hash_func: &Bound<'_, PyAny>,
+        verify: bool,
+    ) -> PyResult<Bloom> {
+        let checksum = if verify {
+            Checksum::Required
+        } else {
+            Checksum::Skip
+        };
+        Bloom::read_from(&mut PyReader(fileobj.clone()), hash_func, checksum)
+    }
+
+    /// Load from a bytes(), see "Persistence" section in the README
+    #[classmethod]
+    fn load_bytes(
+        _cls: &Bound<'_, PyType>,
+        bytes: &[u8],
+        hash_func: &Bound<'_, PyAny>,
+    ) -> PyResult<Bloom> {
+        // check the length up front, as the input may be corrupt
+        if bytes.len() < mem::size_of::<u64>() {
+            return Err(PyValueError::new_err(format!(
+                "serialized bloom filter is truncated: expected at least {} bytes, got {}",
+                mem::size_of::<u64>(),
+                bytes.len()
+            )));
+        }
+        let mut reader = bytes;
+        Bloom::read_complete(&mut reader, hash_func, Checksum::IfPresent)
+    }
+
+    /// Describe the filter that save_bytes (or save, save_to) serialized
+    /// into data from its header alone, without a hash_func or reading
+    /// the bits
+    #[classmethod]
+    #[pyo3(signature = (data, /))]
+    fn inspect_bytes<'py>(cls: &Bound<'py, PyType>, data: &[u8]) -> PyResult<Bound<'py, PyDict>> {
+        // the legacy layout has no header to inspect, so Header::read
+        // can't be trusted to tell it from garbage
+        if !data.starts_with(&Header::MAGIC) {
+            return Err(PyValueError::new_err(format!(
+                "data doesn't start with {:?}, so it isn't a filter serialized by this version \
+                 of rbloom",
+                String::from_utf8_lossy(&Header::MAGIC)
+            )));
+        }
+        let mut reader = data;
+        let header = Header::read(&mut reader)?;
+        let tunables = header.tunables;
+        let info = PyDict::new_bound(cls.py());
+        info.set_item("format_version", Header::VERSION)?;
+        info.set_item("k", header.k)?;
+        info.set_item(
+            "size_in_bits",
+            header.body_len.unwrap_or(0).saturating_mul(8),
+        )?;
+        info.set_item("blocked", header.mode == IndexMode::Blocked)?;
+        info.set_item("hash_id", header.hash_id)?;
+        info.set_item("checksum", header.checksum)?;
+        info.set_item("expected_items", tunables.map(|t| t.expected_items))?;
+        info.set_item(
+            "false_positive_rate",
+            tunables.map(|t| t.false_positive_rate),
+        )?;
+        info.set_item("on_full", tunables.map(|t| t.on_full.name()))?;
+        info.set_item("warn_at_fill", tunables.and_then(|t| t.warn_at_fill))?;
+        Ok(info)
+    }
+
+    /// Save to a file, see "Persistence" section in the README
+    fn save(&self, filepath: PathBuf) -> PyResult<()> {
+        let header = self.header()?;
+        let mut file = File::create(filepath)?;
+        file.write_all(&header.to_bytes())?;
+        self.filter.save(&mut file)?;
+        Ok(())
+    }
+
+    /// Save a snapshot as save does, and then empty the journal, whose
+    /// items the snapshot now holds. The snapshot is written to a temporary
+    /// file first and renamed, so a crash leaves either the old or the new
+    /// one in place.
+    #[pyo3(signature = (snapshot_path, /))]
+    fn checkpoint(&mut self, snapshot_path: PathBuf) -> PyResult<()> {
+        if !self.journal.is_open() {
+            return Err(PyValueError::new_err(
+                "checkpoint needs a filter created with a journal_path or by replay",
+            ));
+        }
+        let mut temp_path = snapshot_path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&self.header()?.to_bytes())?;
+        self.filter.save(&mut file)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, &snapshot_path)?;
+        self.journal.snapshot_path = Some(snapshot_path);
+        self.journal.truncate()
+    }
+
+    /// Load a snapshot written by checkpoint (or save) and add the hashes
+    /// in the journal to it. The filter goes on appending to the journal.
+    #[classmethod]
+    fn replay(
+        _cls: &Bound<'_, PyType>,
+        snapshot_path: PathBuf,
+        journal_path: PathBuf,
+        hash_func: &Bound<'_, PyAny>,
+    ) -> PyResult<Bloom> {
+        let mut bloom = Bloom::read_complete(
+            &mut File::open(&snapshot_path)?,
+            hash_func,
+            Checksum::IfPresent,
+        )?;
+        let mut journal = BufReader::new(File::open(&journal_path)?);
+        let mut record = [0; Journal::RECORD_LEN];
+        loop {
+            match journal.read_exact(&mut record) {
+                Ok(()) => {}
+                // a record cut short by a crash was never acknowledged
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err.into()),
+            }
+            bloom.insert(ItemHash::Custom(i128::from_le_bytes(record)));
+        }
+        bloom.journal = Journal::open(&journal_path)?;
+        bloom.journal.snapshot_path = Some(snapshot_path);
+        Ok(bloom)
+    }
+
+    /// Save to a file object followed by a checksum, see "Persistence"
+    /// section in the README
+    #[pyo3(signature = (fileobj, /))]
+    fn save_to(&self, fileobj: &Bound<'_, PyAny>) -> PyResult<()> {
+        let py = fileobj.py();
+        let mut header = self.header()?;
+        header.checksum = true;
+        let header = header.to_bytes();
+        fileobj.call_method1("write", (PyBytes::new_bound(py, &header),))?;
+        for chunk in self.filter.bits().chunks(WRITE_CHUNK_SIZE) {
+            fileobj.call_method1("write", (PyBytes::new_bound(py, chunk),))?;
+        }
+        let checksum = crc32::update(crc32::update(0, &header), self.filter.bits());
+        fileobj.call_method1("write", (PyBytes::new_bound(py, &checksum.to_le_bytes()),))?;
+        Ok(())
+    }
+
+    /// Save only the bytes that changed since previous, see "Persistence"
+    /// section in the README
+    #[pyo3(signature = (filepath, previous, /))]
+    fn save_delta(&self, filepath: PathBuf, previous: &Bloom) -> PyResult<()> {
+        check_compatible(self, previous)?;
+        let header = self.header()?;
+        let (mask, values) = self.filter.delta_from(&previous.filter);
+        let mut file = File::create(filepath)?;
+        file.write_all(&DELTA_MAGIC)?;
+        file.write_all(&header.to_bytes())?;
+        file.write_all(&mask)?;
+        file.write_all(&values)?;
+        Ok(())
+    }
+
+    /// Load from a file and apply a delta written by save_delta, see
+    /// "Persistence" section in the README
+    #[classmethod]
+    fn apply_delta(
+        _cls: &Bound<'_, PyType>,
+        base_filepath: PathBuf,
+        delta_filepath: PathBuf,
+        hash_func: &Bound<'_, PyAny>,
+    ) -> PyResult<Bloom> {
+        let mut bloom = Bloom::read_complete(
+            &mut File::open(base_filepath)?,
+            hash_func,
+            Checksum::IfPresent,
+        )?;
+        let mut delta = File::open(delta_filepath)?;
+
+        let mut magic = [0; 4];
+        read_exact_or_truncated(&mut delta, &mut magic, "delta")?;
+        if magic != DELTA_MAGIC {
+            return Err(PyValueError::new_err("not a bloom filter delta"));
+        }
+        let header = Header::read(&mut delta)?;
+        if header.mode != bloom.mode
+            || header.k != bloom.k
+            || header.body_len != Some(bloom.filter.bits().len() as u64)
+            || header.hash_id != bloom.hash_id
+        {
+            return Err(PyValueError::new_err(
+                "the delta was saved from a filter with different parameters",
+            ));
+        }
+
+        let mut mask = vec![0; bloom.filter.bits().len().div_ceil(8)];
+        read_exact_or_truncated(&mut delta, &mut mask, "delta")?;
+        let changed = mask.iter().map(|x| x.count_ones() as usize).sum();
+        let mut values = vec![0; changed];
+        read_exact_or_truncated(&mut delta, &mut values, "delta")?;
+        if delta.read(&mut [0])? != 0 {
+            return Err(PyValueError::new_err(
+                "serialized bloom filter delta has trailing data",
+            ));
+        }
+
+        bloom.filter.apply_delta(&mask, &values);
+        Ok(bloom)
+    }
+
+    /// SHA-256 of the number of hash functions (as 8 little-endian bytes),
+    /// a byte that is 1 for blocked filters and 0 otherwise, and the filter
+    /// bits. It only depends on what the filter would answer to lookups, so
+    /// filters that are structurally equal have the same fingerprint no
+    /// matter how they were built.
+    fn fingerprint<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let mut prefix = [0; 9];
+        prefix[..8].copy_from_slice(&self.k.to_le_bytes());
+        prefix[8] = (self.mode == IndexMode::Blocked) as u8;
+
+        let sha256 = py.import_bound("hashlib")?.call_method0("sha256")?;
+        sha256.call_method1("update", (PyBytes::new_bound(py, &prefix),))?;
+        // feed the bits in pieces rather than copying all of them at once
+        for chunk in self.filter.bits().chunks(FINGERPRINT_CHUNK_SIZE) {
+            sha256.call_method1("update", (PyBytes::new_bound(py, chunk),))?;
+        }
+        Ok(sha256.call_method0("digest")?.downcast_into::<PyBytes>()?)
+    }
+
+    /// Iterate over the output of save_bytes in pieces of chunk_size
+    /// bytes, without ever holding all of it in memory
+    #[pyo3(signature = (chunk_size=1 << 20))]
+    fn iter_chunks(slf: &Bound<'_, Self>, chunk_size: usize) -> PyResult<Chunks> {
+        if chunk_size == 0 {
+            return Err(PyValueError::new_err("chunk_size must be greater than 0"));
+        }
+        let bloom = slf.borrow();
+        Ok(Chunks {
+            header: bloom.header()?.to_bytes(),
+            body_len: bloom.filter.bits().len(),
+            bloom: slf.clone().unbind(),
+            position: 0,
+            chunk_size,
+        })
+    }
+
+    /// Save to a byte(), see "Persistence" section in the README
+    fn save_bytes<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let header = self.header()?.to_bytes();
+        let len = header.len() + self.filter.bits().len();
+        PyBytes::new_bound_with(py, len, |data| {
+            data[..header.len()].copy_from_slice(&header);
+            data[header.len()..].copy_from_slice(self.filter.bits());
+            Ok(())
+        })
+    }
+
+    /// Split the output of save_bytes into n pieces of about equal size,
+    /// each with a header saying where it goes, see "Persistence" section
+    /// in the README
+    #[pyo3(signature = (n, /))]
+    fn to_shards<'py>(&self, py: Python<'py>, n: u32) -> PyResult<Vec<Bound<'py, PyBytes>>> {
+        let header = self.header()?.to_bytes();
+        let bits = self.filter.bits();
+        let total = header.len() + bits.len();
+        if n == 0 || n as usize > total {
+            return Err(PyValueError::new_err(format!(
+                "n must be between 1 and the serialized size of {} bytes",
+                total
+            )));
+        }
+        let shard_header = ShardHeader {
+            index: 0,
+            count: n,
+            total_len: total as u64,
+            checksum: crc32::update(crc32::update(0, &header), bits),
+        };
+        (0..n)
+            .map(|index| {
+                let start = total * index as usize / n as usize;
+                let end = total * (index as usize + 1) / n as usize;
+                let shard_header = ShardHeader {
+                    index,
+                    ..shard_header
+                }
+                .to_bytes();
+                // the piece of the serialized filter from start to end
+                let len = header.len();
+                let header = &header[start.min(len)..end.min(len)];
+                let bits = &bits[start.saturating_sub(len)..end.saturating_sub(len)];
+                PyBytes::new_bound_with(py, SHARD_HEADER_LEN + end - start, |data| {
+                    let (data_header, data) = data.split_at_mut(SHARD_HEADER_LEN);
+                    data_header.copy_from_slice(&shard_header);
+                    data[..header.len()].copy_from_slice(header);
+                    data[header.len()..].copy_from_slice(bits);
+                    Ok(())
+                })
+            })
+            .collect()
+    }
+
+    /// Reassemble the shards written by to_shards, which have to be given
+    /// in order, see "Persistence" section in the README
+    #[classmethod]
+    fn from_shards(
+        _cls: &Bound<'_, PyType>,
+        shards: &Bound<'_, PyAny>,
+        hash_func: &Bound<'_, PyAny>,
+    ) -> PyResult<Bloom> {
+        let mut data = Vec::new();
+        let mut first: Option<ShardHeader> = None;
+        for (index, shard) in shards.iter()?.enumerate() {
+            let shard = shard?;
+            let shard = shard
+                .downcast::<PyBytes>()
+                .map_err(|_| PyTypeError::new_err("shards must be bytes"))?
+                .as_bytes();
+            let header = ShardHeader::read(shard)?;
+            let first = first.get_or_insert(header);
+            if (header.count, header.total_len, header.checksum)
+                != (first.count, first.total_len, first.checksum)
+            {
+                return Err(PyValueError::new_err(format!(
+                    "shard {} belongs to a different filter than the first shard",
+                    index
+                )));
+            }
+            if header.index as usize != index {
+                return Err(PyValueError::new_err(format!(
+                    "shards are out of order or missing: expected shard {}, got shard {} of {}",
+                    index, header.index, header.count
+                )));
+            }
+            data.extend_from_slice(&shard[SHARD_HEADER_LEN..]);
+        }
+        let first =
+            first.ok_or_else(|| PyValueError::new_err("no shards to reassemble a filter from"))?;
+        if data.len() as u64 != first.total_len {
+            return Err(PyValueError::new_err(format!(
+                "shards are missing: got {} of {} bytes, expected {} shards",
+                data.len(),
+                first.total_len,
+                first.count
+            )));
+        }
+        if crc32::update(0, &data) != first.checksum {
+            return Err(PyValueError::new_err(
+                "serialized bloom filter is corrupt: its checksum doesn't match",
+            ));
+        }
+        Bloom::read_complete(&mut data.as_slice(), hash_func, Checksum::IfPresent)
+    }
+
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        visit.call(&self.hash_func)?;
+        for key in self.exclusions.values().flatten() {
+            visit.call(key)?;
+        }
+        Ok(())
+    }
+}
+
+// Non-python methods
+impl Bloom {
+    fn hash_fn_clone(&self, py: Python<'_>) -> Option<Py<PyAny>> {
+        self.hash_func.as_ref().map(|f| f.clone_ref(py))
+    }
+
+    /// read_from for data that holds nothing but the filter, such as a file
+    /// or a bytes, which is checked to end after it. Streams like sockets
+    /// don't get this check, as it would wait for more data.
+    fn read_complete(
+        reader: &mut impl Read,
+        hash_func: &Bound<'_, PyAny>,
+        checksum: Checksum,
+    ) -> PyResult<Bloom> {
+        let bloom = Bloom::read_from(reader, hash_func, checksum)?;
+        if reader.read(&mut [0])? != 0 {
+            return Err(PyValueError::new_err(
+                "serialized bloom filter has trailing data",
+            ));
+        }
+        Ok(bloom)
+    }
+
+    /// Shared by the load methods, reads a header followed by the bits and
+    /// the checksum if there is one, and leaves anything after them unread
+    fn read_from(
+        reader: &mut impl Read,
+        hash_func: &Bound<'_, PyAny>,
+        checksum: Checksum,
+    ) -> PyResult<Bloom> {
+        // check that the hash_func is callable
+        if !hash_func.is_callable() {
+            return Err(PyTypeError::new_err("hash_func must be callable"));
+        }
+        // check that the hash_func isn't the built-in hash function
+        if hash_func.is(builtin_hash_func(hash_func.py())?) {
+            return Err(PyValueError::new_err(
+                "Cannot load a bloom filter that uses the built-in hash function",
+            ));
+        }
+        let hash_func = Some(hash_func.to_object(hash_func.py()));
+
+        let header = Header::read(reader)?;
+        let filter = BitLine::load(reader, header.body_len)?;
+        if header.checksum {
+            let mut stored = [0; 4];
+            read_exact_or_truncated(reader, &mut stored, "checksum")?;
+            let computed = crc32::update(crc32::update(0, &header.to_bytes()), filter.bits());
+            if checksum != Checksum::Skip && u32::from_le_bytes(stored) != computed {
+                return Err(PyValueError::new_err(
+                    "serialized bloom filter is corrupt: its checksum doesn't match",
+                ));
+            }
+        } else if checksum == Checksum::Required {
+            return Err(PyValueError::new_err(
+                "serialized bloom filter has no checksum to verify, as it wasn't \
+                 written by save_to; pass verify=False to load it anyway",
+            ));
+        }
+        if header.mode == IndexMode::Blocked && filter.len() % lcg::BLOCK_BITS != 0 {
+            return Err(PyValueError::new_err(
+                "serialized blocked bloom filter isn't made up of whole blocks",
+            ));
+        }
+
+        if header.k == 0 {
+            return Err(PyValueError::new_err(
+                "serialized bloom filter has no hash functions",
+            ));
+        }
+
+        // older files don't record what the filter was designed for, so
+        // assume the capacity for which its size and k are optimal
+        let tunables = header.tunables.unwrap_or_else(|| {
+            let (expected_items, false_positive_rate) = assumed_design(filter.len(), header.k);
+            Tunables {
+                expected_items,
+                false_positive_rate,
+                on_full: OnFull::Ignore,
+                warn_at_fill: None,
+            }
+        });
+        Ok(Bloom {
+            filter,
+            k: header.k,
+            hash_func,
+            mode: header.mode,
+            expected_items: tunables.expected_items,
+            false_positive_rate: tunables.false_positive_rate,
+            fill_warning: tunables.warn_at_fill.map(|threshold| FillWarning {
+                threshold,
+                warned: false,
+            }),
+            hash_id: header.hash_id,
+            on_full: tunables.on_full,
+            exclusions: HashMap::new(),
+            journal: Journal::default(),
+        })
+    }
+
+    /// Header for save and save_bytes
+    fn header(&self) -> PyResult<Header> {
+        if self.hash_func.is_none() {
+            return Err(PyValueError::new_err(
+                "Cannot save a bloom filter that uses the built-in hash function",
+            ));
+        }
+        Ok(Header {
+            mode: self.mode,
+            k: self.k,
+            body_len: Some(self.filter.bits().len() as u64),
+            hash_id: self.hash_id.clone(),
+            tunables: Some(Tunables {
+                expected_items: self.expected_items,
+                false_positive_rate: self.false_positive_rate,
+                on_full: self.on_full,
+                warn_at_fill: self.fill_warning.as_ref().map(|w| w.threshold),
+            }),
+            checksum: false,
+        })
+    }
+
+    /// Interprets h the way hash() would have if it had computed it
+    fn precomputed_hash(&self, h: &Bound<'_, PyAny>) -> PyResult<ItemHash> {
+        match self.hash_func {
+            Some(_) => Ok(ItemHash::Custom(custom_hash_value(h)?)),
+            None => match i64::try_from(h.extract::<i128>()?) {
+                Ok(h) => Ok(ItemHash::Builtin(h as u64)),
+                Err(_) => Err(PyValueError::new_err(
+                    "hashes for a filter using the built-in hash function must fit in 64 bits",
+                )),
+            },
+        }
+    }
+
+    /// Inserts each of the native-endian 64-bit integers in data as if it
+    /// had been passed to add_hash. Doesn't touch any Python objects, so
+    /// it can run without the GIL.
+    fn insert_raw_hashes(&mut self, data: &[u8], signed: bool) -> PyResult<()> {
+        for value in data.chunks_exact(8) {
+            let value = u64::from_ne_bytes(value.try_into().unwrap());
+            let hash =
+                match (&self.hash_func, signed) {
+                    (Some(_), true) => ItemHash::Custom(value as i64 as i128),
+                    (Some(_), false) => ItemHash::Custom(value as i128),
+                    (None, false) if value > i64::MAX as u64 => return Err(PyValueError::new_err(
+                        "hashes for a filter using the built-in hash function must fit in 64 bits",
+                    )),
+                    (None, _) => ItemHash::Builtin(value),
+                };
+            self.insert(hash);
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, hash: ItemHash) {
+        let filter = &mut self.filter;
+        hash.for_each_index(self.mode, self.k, filter.len(), |index| filter.set(index));
+    }
+
+    /// Applies on_full before an item is inserted. The number of items is
+    /// estimated as for a non-blocked filter, which only needs the number
+    /// of set bits.
+    fn make_room(&mut self, py: Python<'_>) -> PyResult<()> {
+        if self.on_full == OnFull::Ignore
+            || estimate_items(self.filter.sum(), self.filter.len(), self.k)
+                < self.expected_items as f64
+        {
+            return Ok(());
+        }
+        if self.on_full == OnFull::Reject {
+            return Err(PyRuntimeError::new_err(format!(
+                "the bloom filter is full, as it holds about expected_items={} items",
+                self.expected_items
+            )));
+        }
+        self.check_journal("on_full=\"rotate\" clearing the filter")?;
+        let message = format!(
+            "the bloom filter holds about expected_items={} items, so it was cleared",
+            self.expected_items
+        );
+        PyErr::warn_bound(py, &py.get_type_bound::<PyUserWarning>(), &message, 1)?;
+        self.clear()
+    }
+
+    /// Emits the warning requested with warn_at_fill if the filter has just
+    /// become full enough
+    fn check_fill(&mut self, py: Python<'_>) -> PyResult<()> {
+        let Some(warning) = self.fill_warning.as_mut().filter(|w| !w.warned) else {
+            return Ok(());
+        };
+        let fill = self.filter.sum() as f64 / self.filter.len() as f64;
+        if fill >= warning.threshold {
+            warning.warned = true;
+            let message = format!(
+                "the bloom filter is {:.1}% full, past warn_at_fill={}, so its false \
+                 positive rate is rising above {:.3e}; use a larger filter",
+                fill * 100.0,
+                warning.threshold,
+                self.false_positive_rate
+            );
+            PyErr::warn_bound(py, &py.get_type_bound::<PyUserWarning>(), &message, 1)?;
+        }
+        Ok(())
+    }
+
+    /// Re-arms the warning once the filter is empty again
+    fn reset_fill_warning(&mut self) {
+        if let Some(warning) = &mut self.fill_warning {
+            warning.warned = false;
+        }
+    }
+
+    /// Returns whether all of the item's bits were already set
+    fn insert_checked(&mut self, hash: ItemHash) -> bool {
+        let filter = &mut self.filter;
+        let mut all_set = true;
+        hash.for_each_index(self.mode, self.k, filter.len(), |index| {
+            all_set &= filter.test_and_set(index);
+        });
+        all_set
+    }
+
+    /// Whether o, whose hash is given, was passed to add_exclusion
+    fn is_excluded(&self, o: &Bound<'_, PyAny>, hash: ItemHash) -> PyResult<bool> {
+        if let Some(keys) = self.exclusions.get(&hash) {
+            for key in keys {
+                if key.bind(o.py()).eq(o)? {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn contains(&self, hash: ItemHash) -> bool {
+        hash.all_indexes(self.mode, self.k, self.filter.len(), |index| {
+            self.filter.get(index)
+        })
+    }
+
+    fn zeroed_clone(&self, py: Python<'_>) -> Bloom {
+        Bloom {
+            filter: BitLine::new(self.filter.len()).unwrap(),
+            k: self.k,
+            hash_func: self.hash_fn_clone(py),
+            mode: self.mode,
+            expected_items: self.expected_items,
+            false_positive_rate: self.false_positive_rate,
+            fill_warning: None,
+            hash_id: self.hash_id.clone(),
+            on_full: OnFull::Ignore,
+            exclusions: HashMap::new(),
+            journal: Journal::default(),
+        }
+    }
+
+    /// Like zeroed_clone, but keeping what the filter does once it's full
+    fn empty_copy(&self, py: Python<'_>) -> Bloom {
+        let mut result = Bloom {
+            fill_warning: self.fill_warning.clone(),
+            on_full: self.on_full,
+            ..self.zeroed_clone(py)
+        };
+        result.reset_fill_warning();
+        result
+    }
+
+    fn union_with(&mut self, other: &Bound<'_, PyAny>) -> PyResult<()> {
+        // If the other object is a Bloom, use the bitwise union
+        if let Ok(other) = other.downcast::<Bloom>() {
+            let other = other.try_borrow()?;
+            check_compatible(self, &other)?;
+            self.check_journal("a union with another Bloom")?;
+            let (filter, other_filter) = (&mut self.filter, &other.filter);
+            // large filters are combined on several threads
+            other.py().allow_threads(|| *filter |= other_filter);
+            self.checkpoint_journal()?;
+        }
+        // Otherwise, iterate over the other object and add each item
+        else {
+            for obj in other.iter()? {
+                self.add(&obj?)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Refuses a change that can't be recorded in the journal unless there is
+    /// a snapshot to checkpoint to once it's made
+    fn check_journal(&self, change: &str) -> PyResult<()> {
+        if self.journal.is_open() && self.journal.snapshot_path.is_none() {
+            return Err(PyValueError::new_err(format!(
+                "{} can't be recorded in the journal, call checkpoint first",
+                change
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checkpoints to the last snapshot after a change that check_journal
+    /// allowed
+    fn checkpoint_journal(&mut self) -> PyResult<()> {
+        match self.journal.snapshot_path.clone() {
+            Some(path) if self.journal.is_open() => self.checkpoint(path),
+            _ => Ok(()),
+        }
+    }
+
+    /// temp is reused across calls to avoid reallocating it for each iterable
+    fn intersect_with(
+        &mut self,
+        other: &Bound<'_, PyAny>,
+        temp: &mut Option<Self>,
+    ) -> PyResult<()> {
+        self.check_journal("an intersection")?;
+        // If the other object is a Bloom, use the bitwise intersection
+        if let Ok(other) = other.downcast::<Bloom>() {
+            let other = other.try_borrow()?;
+            check_compatible(self, &other)?;
+            let (filter, other_filter) = (&mut self.filter, &other.filter);
+            other.py().allow_threads(|| *filter &= other_filter);
+        }
+        // Otherwise, add each item to a temporary bloom and intersect with it
+        else {
+            let temp = temp.get_or_insert_with(|| self.zeroed_clone(other.py()));
+            temp.clear()?;
+            for obj in other.iter()? {
+                temp.add(&obj?)?;
+            }
+            self.filter &= &temp.filter;
+        }
+        self.checkpoint_journal()
     }
 
     /// Extract other as a bloom, or iterate other, and add all items to a temporary bloom
@@ -391,48 +1831,899 @@ impl Bloom {
     }
 }
 
-/// This is a primitive BitVec-like structure that uses a `Box<[u8]>` as
-/// the backing store; it exists here to avoid the need for a dependency
-/// on bitvec and to act as a container around all the bit manipulation.
-/// Indexing is done using u64 to avoid address space issues on 32-bit
-/// systems, which would otherwise limit the size to 2^32 bits (512MB).
-/// Using u8 for the backing store simplifies file I/O as well as file
-/// portability across systems, and the performance is equivalent to
-/// using usize, even though the latter is arguably more elegant.
-mod bitline {
+/// The file that the hash of every item added to a Bloom is appended to,
+/// 16 little-endian bytes each, so that the filter can be rebuilt from a
+/// snapshot plus the journal after a crash. Copies of the filter don't
+/// write to it, so cloning gives a closed journal. snapshot_path is where
+/// the filter was last checkpointed to or replayed from.
+#[derive(Default)]
+struct Journal {
+    file: Option<BufWriter<File>>,
+    snapshot_path: Option<PathBuf>,
+}
+
+impl Clone for Journal {
+    fn clone(&self) -> Self {
+        Journal::default()
+    }
+}
+
+impl Journal {
+    const RECORD_LEN: usize = 16;
+
+    /// Opens the journal for appending, creating it if needed
+    fn open(path: &Path) -> PyResult<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        // drop a record cut short by a crash, which would misalign every
+        // record after it
+        let len = file.metadata()?.len();
+        file.set_len(len - len % Self::RECORD_LEN as u64)?;
+        Ok(Journal {
+            file: Some(BufWriter::new(file)),
+            snapshot_path: None,
+        })
+    }
+
+    fn is_open(&self) -> bool {
+        self.file.is_some()
+    }
+
+    fn record(&mut self, hash: ItemHash) -> PyResult<()> {
+        if let Some(file) = &mut self.file {
+            let value = match hash {
+                ItemHash::Builtin(h) => h as i128,
+                ItemHash::Custom(h) => h,
+            };
+            file.write_all(&value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Hands the records written so far to the OS, so that they survive
+    /// the process crashing
+    fn flush(&mut self) -> PyResult<()> {
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self) -> PyResult<()> {
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+            file.get_ref().set_len(0)?;
+            file.get_ref().sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Calls the progress callback given to one of Bloom's bulk methods with the
+/// number of items added so far, every `every` items and once at the end
+struct Progress<'py> {
+    callback: Option<Bound<'py, PyAny>>,
+    every: u64,
+    count: u64,
+}
+
+impl<'py> Progress<'py> {
+    fn new(callback: Option<Bound<'py, PyAny>>, every: u64) -> PyResult<Self> {
+        if every == 0 {
+            return Err(PyValueError::new_err(
+                "progress_every must be greater than 0",
+            ));
+        }
+        if callback
+            .as_ref()
+            .is_some_and(|callback| !callback.is_callable())
+        {
+            return Err(PyTypeError::new_err("progress must be callable"));
+        }
+        Ok(Progress {
+            callback,
+            every,
+            count: 0,
+        })
+    }
+
+    /// Counts items that were added, calling the callback if it's due
+    fn advance(&mut self, items: u64) -> PyResult<()> {
+        let before = self.count / self.every;
+        self.count += items;
+        match &self.callback {
+            Some(callback) if self.count / self.every != before => {
+                callback.call1((self.count,))?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Calls the callback for the items added since it was last called
+    fn finish(&self) -> PyResult<()> {
+        match &self.callback {
+            Some(callback) if !self.count.is_multiple_of(self.every) => {
+                callback.call1((self.count,))?;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// State of the warning requested with Bloom's warn_at_fill
+#[derive(Clone)]
+struct FillWarning {
+    /// Fraction of set bits at which to warn
+    threshold: f64,
+    warned: bool,
+}
+
+/// What Bloom does with items added once it holds about expected_items.
+/// The values are those saved in the Header.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OnFull {
+    /// Add them anyway, letting the false positive rate rise
+    Ignore = 0,
+    /// Raise a RuntimeError
+    Reject = 1,
+    /// Clear the filter with a warning, and then add them
+    Rotate = 2,
+}
+
+impl OnFull {
+    fn parse(name: &str) -> PyResult<Self> {
+        match name {
+            "ignore" => Ok(OnFull::Ignore),
+            "reject" => Ok(OnFull::Reject),
+            "rotate" => Ok(OnFull::Rotate),
+            _ => Err(PyValueError::new_err(format!(
+                "on_full must be \"ignore\", \"reject\" or \"rotate\", got {:?}",
+                name
+            ))),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            OnFull::Ignore => "ignore",
+            OnFull::Reject => "reject",
+            OnFull::Rotate => "rotate",
+        }
+    }
+}
+
+/// Iterator returned by Bloom.iter_chunks. It reads the filter as it goes,
+/// so modifying the filter during the iteration mixes old and new bits,
+/// and resizing it ends the iteration with an error.
+#[pyclass(module = "rbloom")]
+struct Chunks {
+    bloom: Py<Bloom>,
+    header: Vec<u8>,
+    body_len: usize,
+    /// Offset into the serialized form, which is the header followed by
+    /// the filter bits
+    position: usize,
+    chunk_size: usize,
+}
+
+#[pymethods]
+impl Chunks {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__<'py>(&mut self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyBytes>>> {
+        let bloom = self.bloom.try_borrow(py)?;
+        if bloom.filter.bits().len() != self.body_len {
+            return Err(PyRuntimeError::new_err(
+                "bloom filter changed size during iteration",
+            ));
+        }
+        let header = &self.header[self.position.min(self.header.len())..];
+        let bits = &bloom.filter.bits()[self.position.saturating_sub(self.header.len())..];
+        if header.is_empty() && bits.is_empty() {
+            return Ok(None);
+        }
+        let header = &header[..header.len().min(self.chunk_size)];
+        let bits = &bits[..bits.len().min(self.chunk_size - header.len())];
+        self.position += header.len() + bits.len();
+
+        let chunk = PyBytes::new_bound_with(py, header.len() + bits.len(), |data| {
+            data[..header.len()].copy_from_slice(header);
+            data[header.len()..].copy_from_slice(bits);
+            Ok(())
+        })?;
+        Ok(Some(chunk))
+    }
+
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        visit.call(&self.bloom)?;
+        Ok(())
+    }
+}
+
+/// A list of compatible Blooms, such as the filters of the shards of a data
+/// set, that can be asked which of them contain an item all at once
+#[pyclass(module = "rbloom")]
+struct BloomSet {
+    filters: Vec<Py<Bloom>>,
+}
+
+#[pymethods]
+impl BloomSet {
+    #[new]
+    #[pyo3(signature = (filters=None))]
+    fn new(filters: Option<&Bound<'_, PyAny>>) -> PyResult<Self> {
+        let mut set = BloomSet {
+            filters: Vec::new(),
+        };
+        if let Some(filters) = filters {
+            for bloom in filters.iter()? {
+                set.append(bloom?.downcast()?)?;
+            }
+        }
+        Ok(set)
+    }
+
+    /// Add a filter, which must be compatible with those already in the set
+    #[pyo3(signature = (bloom, /))]
+    fn append(&mut self, bloom: &Bound<'_, Bloom>) -> PyResult<()> {
+        if let Some(first) = self.filters.first() {
+            check_compatible(&*first.try_borrow(bloom.py())?, &*bloom.try_borrow()?)?;
+        }
+        self.filters.push(bloom.clone().unbind());
+        Ok(())
+    }
+
+    /// Indices of the filters that (probably) contain o. The item is hashed
+    /// and its indexes are computed once for all of the filters.
+    #[pyo3(signature = (o, /))]
+    fn which_contain(&self, py: Python<'_>, o: &Bound<'_, PyAny>) -> PyResult<Vec<usize>> {
+        let Some(first) = self.filters.first() else {
+            return Ok(Vec::new());
+        };
+        let first = first.try_borrow(py)?;
+        let mut indexes = Vec::with_capacity(first.k as usize);
+        let hash = hash(o, &first.hash_func)?;
+        hash.for_each_index(first.mode, first.k, first.filter.len(), |i| indexes.push(i));
+
+        let mut result = Vec::new();
+        for (i, bloom) in self.filters.iter().enumerate() {
+            let bloom = bloom.try_borrow(py)?;
+            // the filters may have been resized since they were appended
+            check_compatible(&first, &bloom)?;
+            if indexes.iter().all(|&index| bloom.filter.get(index))
+                && !bloom.is_excluded(o, hash)?
+            {
+                result.push(i);
+            }
+        }
+        Ok(result)
+    }
+
+    fn __len__(&self) -> usize {
+        self.filters.len()
+    }
+
+    /// The filter at an index, which may be negative as for a list
+    fn __getitem__(&self, py: Python<'_>, index: isize) -> PyResult<Py<Bloom>> {
+        let len = self.filters.len() as isize;
+        let index = if index < 0 { index + len } else { index };
+        match usize::try_from(index)
+            .ok()
+            .and_then(|i| self.filters.get(i))
+        {
+            Some(bloom) => Ok(bloom.clone_ref(py)),
+            None => Err(PyIndexError::new_err("BloomSet index out of range")),
+        }
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<BloomSet filters={}>", self.filters.len())
+    }
+
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        for bloom in &self.filters {
+            visit.call(bloom)?;
+        }
+        Ok(())
+    }
+}
+
+/// The union of compatible Blooms for querying only, which checks each item
+/// in the filters one after the other instead of merging them into one
+#[pyclass(module = "rbloom")]
+struct UnionView {
+    filters: Vec<Py<Bloom>>,
+}
+
+#[pymethods]
+impl UnionView {
+    #[new]
+    #[pyo3(signature = (filters, /))]
+    fn new(filters: &Bound<'_, PyAny>) -> PyResult<Self> {
+        let mut view = UnionView {
+            filters: Vec::new(),
+        };
+        for bloom in filters.iter()? {
+            let bloom = bloom?;
+            let bloom = bloom.downcast::<Bloom>()?;
+            if let Some(first) = view.filters.first() {
+                check_compatible(&*first.try_borrow(bloom.py())?, &*bloom.try_borrow()?)?;
+            }
+            view.filters.push(bloom.clone().unbind());
+        }
+        Ok(view)
+    }
+
+    /// Whether any of the filters (probably) contains o. The item is hashed
+    /// and its indexes are computed once for all of the filters, and the
+    /// filters after the first one that contains it aren't looked at.
+    fn __contains__(&self, py: Python<'_>, o: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let Some(first) = self.filters.first() else {
+            return Ok(false);
+        };
+        let first = first.try_borrow(py)?;
+        let mut indexes = Vec::with_capacity(first.k as usize);
+        let hash = hash(o, &first.hash_func)?;
+        hash.for_each_index(first.mode, first.k, first.filter.len(), |i| indexes.push(i));
+
+        for bloom in &self.filters {
+            let bloom = bloom.try_borrow(py)?;
+            // the filters may have been resized since the view was made
+            check_compatible(&first, &bloom)?;
+            if indexes.iter().all(|&index| bloom.filter.get(index))
+                && !bloom.is_excluded(o, hash)?
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn __len__(&self) -> usize {
+        self.filters.len()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("<UnionView filters={}>", self.filters.len())
+    }
+
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        for bloom in &self.filters {
+            visit.call(bloom)?;
+        }
+        Ok(())
+    }
+}
+
+/// A Bloom filter with a small saturating counter in place of each bit, so
+/// that items which are known to have been added can be removed again
+#[pyclass(module = "rbloom")]
+#[derive(Clone)]
+struct CountingBloom {
+    counters: CounterLine,
+    k: u64,
+    hash_func: Option<Py<PyAny>>,
+}
+
+#[pymethods]
+impl CountingBloom {
+    #[new]
+    fn new(
+        expected_items: u64,
+        false_positive_rate: f64,
+        hash_func: Option<Bound<'_, PyAny>>,
+    ) -> PyResult<Self> {
+        let (num_counters, k) = filter_parameters(expected_items, false_positive_rate)?;
+        Ok(CountingBloom {
+            counters: CounterLine::new(num_counters)?,
+            k,
+            hash_func: custom_hash_func(hash_func)?,
+        })
+    }
+
+    /// Number of counters in the filter, each of which takes up a byte
+    #[getter]
+    fn num_counters(&self) -> u64 {
+        self.counters.len()
+    }
+
+    /// Retrieve the hash_func given to __init__
+    #[getter]
+    fn hash_func<'py>(&self, py: Python<'py>) -> PyResult<&Bound<'py, PyAny>> {
+        match self.hash_func.as_ref() {
+            Some(hash_func) => Ok(hash_func.bind(py)),
+            None => builtin_hash_func(py),
+        }
+    }
+
+    #[pyo3(signature = (o, /))]
+    fn add(&mut self, o: &Bound<'_, PyAny>) -> PyResult<()> {
+        let hash = hash(o, &self.hash_func)?;
+        let counters = &mut self.counters;
+        hash.for_each_index(IndexMode::Standard, self.k, counters.len(), |index| {
+            counters.increment(index)
+        });
+        Ok(())
+    }
+
+    fn __contains__(&self, o: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let hash = hash(o, &self.hash_func)?;
+        Ok(self.contains(hash))
+    }
+
+    /// Remove each of the keys, which must actually have been added before
+    ///
+    /// Keys that are definitely not in the filter are skipped. Keys that are
+    /// false positives can't be told apart from added ones, and removing them
+    /// may cause false negatives for the items they collide with.
+    #[pyo3(signature = (keys, /))]
+    fn difference_update(&mut self, keys: &Bound<'_, PyAny>) -> PyResult<()> {
+        for key in keys.iter()? {
+            let hash = hash(&key?, &self.hash_func)?;
+            if self.contains(hash) {
+                let counters = &mut self.counters;
+                hash.for_each_index(IndexMode::Standard, self.k, counters.len(), |index| {
+                    counters.decrement(index)
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.counters.clear();
+    }
+
+    fn copy(&self) -> CountingBloom {
+        self.clone()
+    }
+
+    fn __repr__(&self) -> String {
+        // Use a format that makes it clear that the object
+        // cannot be reconstructed from the repr
+        format!("<CountingBloom num_counters={}>", self.num_counters())
+    }
+
+    fn __bool__(&self) -> bool {
+        !self.counters.is_empty()
+    }
+
+    #[classattr]
+    const __hash__: Option<Py<PyAny>> = None;
+
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        visit.call(&self.hash_func)?;
+        Ok(())
+    }
+}
+
+// Non-python methods
+impl CountingBloom {
+    fn contains(&self, hash: ItemHash) -> bool {
+        hash.all_indexes(IndexMode::Standard, self.k, self.counters.len(), |index| {
+            self.counters.get(index) != 0
+        })
+    }
+}
+
+/// A Stable Bloom filter (Deng and Rafiei, 2006) for unbounded streams: each
+/// add first decrements a number of randomly chosen counters and then sets
+/// the item's counters to max_value, so that items which aren't added again
+/// are eventually forgotten and the filter never fills up
+#[pyclass(module = "rbloom")]
+#[derive(Clone)]
+struct StableBloom {
+    counters: CounterLine,
+    k: u64,
+    hash_func: Option<Py<PyAny>>,
+    decrements: u64,
+    max_value: u8,
+    /// Picks the counters to decrement
+    random: lcg::Random64,
+}
+
+#[pymethods]
+impl StableBloom {
+    #[new]
+    #[pyo3(signature = (expected_items, false_positive_rate, hash_func=None, *, decrements=None, max_value=3))]
+    fn new(
+        expected_items: u64,
+        false_positive_rate: f64,
+        hash_func: Option<Bound<'_, PyAny>>,
+        decrements: Option<u64>,
+        max_value: u8,
+    ) -> PyResult<Self> {
+        let (num_counters, k) = filter_parameters(expected_items, false_positive_rate)?;
+        if max_value == 0 {
+            return Err(PyValueError::new_err("max_value must be greater than 0"));
+        }
+        // a counter set to max_value survives about max_value *
+        // num_counters / decrements adds, which is expected_items by default
+        let decrements = match decrements {
+            Some(decrements) => decrements,
+            None => (max_value as u64)
+                .checked_mul(num_counters)
+                .ok_or_else(|| PyValueError::new_err("too many counters"))?
+                .div_ceil(expected_items),
+        };
+        if decrements == 0 {
+            return Err(PyValueError::new_err("decrements must be greater than 0"));
+        }
+        Ok(StableBloom {
+            counters: CounterLine::new(num_counters)?,
+            k,
+            hash_func: custom_hash_func(hash_func)?,
+            decrements,
+            max_value,
+            random: lcg::distribute_entropy_u64(0),
+        })
+    }
+
+    /// Number of counters in the filter, each of which takes up a byte
+    #[getter]
+    fn num_counters(&self) -> u64 {
+        self.counters.len()
+    }
+
+    /// Number of counters decremented by each add
+    #[getter]
+    fn decrements(&self) -> u64 {
+        self.decrements
+    }
+
+    /// Value that add sets the counters of an item to
+    #[getter]
+    fn max_value(&self) -> u8 {
+        self.max_value
+    }
+
+    /// Retrieve the hash_func given to __init__
+    #[getter]
+    fn hash_func<'py>(&self, py: Python<'py>) -> PyResult<&Bound<'py, PyAny>> {
+        match self.hash_func.as_ref() {
+            Some(hash_func) => Ok(hash_func.bind(py)),
+            None => builtin_hash_func(py),
+        }
+    }
+
+    #[pyo3(signature = (o, /))]
+    fn add(&mut self, o: &Bound<'_, PyAny>) -> PyResult<()> {
+        let hash = hash(o, &self.hash_func)?;
+        let len = self.counters.len();
+        for _ in 0..self.decrements {
+            let index = self.random.next().unwrap() % len;
+            self.counters.decay(index);
+        }
+        let (counters, max_value) = (&mut self.counters, self.max_value);
+        hash.for_each_index(IndexMode::Standard, self.k, len, |index| {
+            counters.set(index, max_value)
+        });
+        Ok(())
+    }
+
+    fn __contains__(&self, o: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let hash = hash(o, &self.hash_func)?;
+        Ok(
+            hash.all_indexes(IndexMode::Standard, self.k, self.counters.len(), |index| {
+                self.counters.get(index) != 0
+            }),
+        )
+    }
+
+    fn clear(&mut self) {
+        self.counters.clear();
+    }
+
+    fn copy(&self) -> StableBloom {
+        self.clone()
+    }
+
+    fn __repr__(&self) -> String {
+        // Use a format that makes it clear that the object
+        // cannot be reconstructed from the repr
+        format!(
+            "<StableBloom num_counters={} decrements={} max_value={}>",
+            self.num_counters(),
+            self.decrements,
+            self.max_value
+        )
+    }
+
+    fn __bool__(&self) -> bool {
+        !self.counters.is_empty()
+    }
+
+    #[classattr]
+    const __hash__: Option<Py<PyAny>> = None;
+
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        visit.call(&self.hash_func)?;
+        Ok(())
+    }
+}
+
+/// A count-min sketch, which estimates how often each item was added: every
+/// item has one counter in each of k rows, add increments all of them and
+/// count returns the smallest, which can only be too high due to other items
+/// sharing the counters
+#[pyclass(module = "rbloom")]
+#[derive(Clone)]
+struct CountMinBloom {
+    counters: Box<[u32]>,
+    k: u64,
+    /// Number of counters per row
+    width: u64,
+    hash_func: Option<Py<PyAny>>,
+}
+
+#[pymethods]
+impl CountMinBloom {
+    #[new]
+    #[pyo3(signature = (expected_items, false_positive_rate, hash_func=None))]
+    fn new(
+        expected_items: u64,
+        false_positive_rate: f64,
+        hash_func: Option<Bound<'_, PyAny>>,
+    ) -> PyResult<Self> {
+        let (num_counters, k) = filter_parameters(expected_items, false_positive_rate)?;
+        let width = num_counters.div_ceil(k);
+        let counters = match (width * k).try_into() {
+            Ok(len) => vec![0; len].into_boxed_slice(),
+            Err(_) => return Err(PyValueError::new_err("too many counters")),
+        };
+        Ok(CountMinBloom {
+            counters,
+            k,
+            width,
+            hash_func: custom_hash_func(hash_func)?,
+        })
+    }
+
+    /// Number of counters in the filter, each of which takes up four bytes
+    #[getter]
+    fn num_counters(&self) -> u64 {
+        self.counters.len() as u64
+    }
+
+    /// Number of rows of counters, one per hash function
+    #[getter]
+    fn rows(&self) -> u64 {
+        self.k
+    }
+
+    /// Retrieve the hash_func given to __init__
+    #[getter]
+    fn hash_func<'py>(&self, py: Python<'py>) -> PyResult<&Bound<'py, PyAny>> {
+        match self.hash_func.as_ref() {
+            Some(hash_func) => Ok(hash_func.bind(py)),
+            None => builtin_hash_func(py),
+        }
+    }
+
+    /// Add the item count times
+    #[pyo3(signature = (o, /, count=1))]
+    fn add(&mut self, o: &Bound<'_, PyAny>, count: u32) -> PyResult<()> {
+        let hash = hash(o, &self.hash_func)?;
+        let counters = &mut self.counters;
+        hash.for_each_row_index(self.k, self.width, |index| {
+            let counter = &mut counters[index as usize];
+            *counter = counter.saturating_add(count);
+        });
+        Ok(())
+    }
+
+    /// Estimate how many times the item was added, which is never too low
+    #[pyo3(signature = (o, /))]
+    fn count(&self, o: &Bound<'_, PyAny>) -> PyResult<u32> {
+        let hash = hash(o, &self.hash_func)?;
+        Ok(self.count_hash(hash))
+    }
+
+    fn __contains__(&self, o: &Bound<'_, PyAny>) -> PyResult<bool> {
+        let hash = hash(o, &self.hash_func)?;
+        Ok(self.count_hash(hash) != 0)
+    }
+
+    fn clear(&mut self) {
+        self.counters.fill(0);
+    }
+
+    fn copy(&self) -> CountMinBloom {
+        self.clone()
+    }
+
+    fn __repr__(&self) -> String {
+        // Use a format that makes it clear that the object
+        // cannot be reconstructed from the repr
+        format!(
+            "<CountMinBloom num_counters={} rows={}>",
+            self.num_counters(),
+            self.k
+        )
+    }
+
+    fn __bool__(&self) -> bool {
+        self.counters.iter().any(|&counter| counter != 0)
+    }
+
+    #[classattr]
+    const __hash__: Option<Py<PyAny>> = None;
+
+    fn __traverse__(&self, visit: PyVisit<'_>) -> Result<(), PyTraverseError> {
+        visit.call(&self.hash_func)?;
+        Ok(())
+    }
+}
+
+impl CountMinBloom {
+    fn count_hash(&self, hash: ItemHash) -> u32 {
+        let mut count = u32::MAX;
+        hash.for_each_row_index(self.k, self.width, |index| {
+            count = count.min(self.counters[index as usize])
+        });
+        count
+    }
+}
+
+/// This is a primitive BitVec-like structure that uses a `Box<[u8]>` as
+/// the backing store; it exists here to avoid the need for a dependency
+/// on bitvec and to act as a container around all the bit manipulation.
+/// Indexing is done using u64 to avoid address space issues on 32-bit
+/// systems, which would otherwise limit the size to 2^32 bits (512MB).
+/// Using u8 for the backing store simplifies file I/O as well as file
+/// portability across systems, and the performance is equivalent to
+/// using usize, even though the latter is arguably more elegant.
+mod bitline {
     use pyo3::exceptions::PyValueError;
     use pyo3::prelude::*;
     use std::fs::File;
     use std::io::{Read, Write};
 
-    #[inline(always)]
-    fn bit_idx(idx: u64) -> Option<(usize, u32)> {
-        let (q, r) = (idx / 8, idx % 8);
-        Some((q.try_into().ok()?, r.try_into().ok()?))
+    #[inline(always)]
+    fn bit_idx(idx: u64) -> Option<(usize, u32)> {
+        let (q, r) = (idx / 8, idx % 8);
+        Some((q.try_into().ok()?, r.try_into().ok()?))
+    }
+
+    /// Alignment of the start of the bits, a typical cache line
+    const ALIGNMENT: usize = 64;
+
+    /// A byte buffer that starts at a multiple of ALIGNMENT, so that the
+    /// blocks of a blocked filter never straddle two cache lines. It
+    /// over-allocates and skips the bytes before the first aligned address.
+    struct AlignedBytes {
+        // never reallocated once the offset has been computed
+        buf: Vec<u8>,
+        start: usize,
+        len: usize,
+    }
+
+    impl AlignedBytes {
+        fn zeroed(len: usize) -> Self {
+            let buf = vec![0; len + ALIGNMENT - 1];
+            let start = buf.as_ptr().align_offset(ALIGNMENT);
+            Self::checked(buf, start, len)
+        }
+
+        /// Moves the data of bytes within its own allocation, so that it
+        /// doesn't need to be copied
+        fn from_vec(mut buf: Vec<u8>) -> Self {
+            let len = buf.len();
+            buf.resize(len + ALIGNMENT - 1, 0);
+            let start = buf.as_ptr().align_offset(ALIGNMENT);
+            buf.copy_within(..len, start);
+            Self::checked(buf, start, len)
+        }
+
+        /// Zeroes the buffer and changes its length
+        fn reset(&mut self, len: usize) {
+            if self.start + len <= self.buf.len() {
+                self.len = len;
+                self.fill(0);
+            } else {
+                // free the old allocation before making the new one
+                self.buf = Vec::new();
+                *self = Self::zeroed(len);
+            }
+        }
+
+        fn checked(buf: Vec<u8>, start: usize, len: usize) -> Self {
+            debug_assert!(start + len <= buf.len());
+            debug_assert_eq!(buf[start..].as_ptr() as usize % ALIGNMENT, 0);
+            Self { buf, start, len }
+        }
+    }
+
+    impl std::ops::Deref for AlignedBytes {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            &self.buf[self.start..self.start + self.len]
+        }
+    }
+
+    impl std::ops::DerefMut for AlignedBytes {
+        fn deref_mut(&mut self) -> &mut [u8] {
+            &mut self.buf[self.start..self.start + self.len]
+        }
+    }
+
+    impl Clone for AlignedBytes {
+        // a plain copy of buf could land at a different alignment
+        fn clone(&self) -> Self {
+            let mut clone = Self::zeroed(self.len);
+            clone.copy_from_slice(self);
+            clone
+        }
+    }
+
+    impl PartialEq for AlignedBytes {
+        fn eq(&self, other: &Self) -> bool {
+            **self == **other
+        }
+    }
+
+    impl Eq for AlignedBytes {}
+
+    #[derive(Clone, Eq)]
+    pub struct BitLine {
+        bits: AlignedBytes,
+        /// Number of set bits, kept up to date by every method that changes
+        /// the bits so that sum doesn't have to count them
+        bits_set: u64,
+    }
+
+    impl PartialEq for BitLine {
+        /// Lines with different numbers of set bits differ without
+        /// comparing the bits
+        fn eq(&self, other: &Self) -> bool {
+            self.bits_set == other.bits_set && self.bits == other.bits
+        }
+    }
+
+    fn count_ones(bits: &[u8]) -> u64 {
+        bits.iter().map(|x| x.count_ones() as u64).sum()
     }
 
-    #[derive(Clone, PartialEq, Eq)]
-    pub struct BitLine {
-        bits: Box<[u8]>,
+    fn byte_len(size_in_bits: u64) -> PyResult<usize> {
+        match bit_idx(size_in_bits) {
+            Some((q, r)) => Ok(if r == 0 { q } else { q + 1 }),
+            None => Err(PyValueError::new_err("too many bits")),
+        }
     }
 
     impl BitLine {
         pub fn new(size_in_bits: u64) -> PyResult<Self> {
-            match bit_idx(size_in_bits) {
-                Some((q, r)) => {
-                    let size = if r == 0 { q } else { q + 1 };
-                    Ok(Self {
-                        bits: vec![0; size].into_boxed_slice(),
-                    })
-                }
-                None => Err(PyValueError::new_err("too many bits")),
-            }
+            Ok(Self {
+                bits: AlignedBytes::zeroed(byte_len(size_in_bits)?),
+                bits_set: 0,
+            })
+        }
+
+        /// Resizes the BitLine and clears all bits, in the existing
+        /// allocation if it is large enough
+        pub fn reset(&mut self, size_in_bits: u64) -> PyResult<()> {
+            self.bits.reset(byte_len(size_in_bits)?);
+            self.bits_set = 0;
+            Ok(())
         }
 
         /// Make sure that index is less than len when calling this!
         pub fn set(&mut self, index: u64) {
+            self.test_and_set(index);
+        }
+
+        /// Sets the bit and returns its previous value. Make sure that
+        /// index is less than len when calling this!
+        pub fn test_and_set(&mut self, index: u64) -> bool {
             let (idx, offset) = bit_idx(index).unwrap();
+            let was_set = self.bits[idx] & (1 << offset) != 0;
             self.bits[idx] |= 1 << offset;
+            self.bits_set += !was_set as u64;
+            was_set
         }
 
         /// Make sure that index is less than len when calling this!
@@ -448,45 +2739,66 @@ mod bitline {
 
         pub fn clear(&mut self) {
             self.bits.fill(0);
+            self.bits_set = 0;
         }
 
+        /// Returns the number of set bits
         pub fn sum(&self) -> u64 {
-            self.bits.iter().map(|x| x.count_ones() as u64).sum()
+            self.bits_set
+        }
+
+        /// Number of set bits in each consecutive block of block_bits,
+        /// which must be a multiple of 8 that divides len.
+        pub fn block_sums(&self, block_bits: u64) -> impl Iterator<Item = u64> + '_ {
+            self.bits.chunks(block_bits as usize / 8).map(count_ones)
         }
 
         pub fn is_empty(&self) -> bool {
-            self.bits.iter().all(|&word| word == 0)
+            self.bits_set == 0
         }
 
+        /// A line with more bits set can't be a subset, so the bits are
+        /// only compared if the counts allow it
         pub fn is_subset(&self, other: &BitLine) -> bool {
-            all_pairs(self, other, |lhs, rhs| (lhs | rhs) == rhs)
+            self.bits_set <= other.bits_set && all_pairs(self, other, |lhs, rhs| (lhs | rhs) == rhs)
         }
 
+        /// A subset with fewer bits set can't be equal
         pub fn is_strict_subset(&self, other: &BitLine) -> bool {
-            let mut is_equal = true;
-            let is_subset = all_pairs(self, other, |lhs, rhs| {
-                is_equal &= lhs == rhs;
-                (lhs | rhs) == rhs
-            });
-            is_subset && !is_equal
+            self.bits_set < other.bits_set && self.is_subset(other)
         }
 
-        /// Reads the given file from the current position to the end and
-        /// returns a BitLine containing the data.
-        pub fn load(file: &mut File) -> PyResult<Self> {
+        /// Reads len bytes from the current position, or everything up to
+        /// the end if the length isn't known, and returns a BitLine
+        /// containing the data. Any data after the len bytes is left for
+        /// the caller to check.
+        pub fn load(reader: &mut impl Read, len: Option<u64>) -> PyResult<Self> {
             let mut bits = Vec::new();
-            file.read_to_end(&mut bits)?;
-            Ok(Self {
-                bits: bits.into_boxed_slice(),
-            })
-        }
-
-        /// Given the provided [u8]
-        /// returns a BitLine containing the data.
-        pub fn load_bytes(bytes: &[u8]) -> PyResult<Self> {
-            let bits = bytes.to_vec();
+            match len {
+                Some(len) => {
+                    reader.take(len).read_to_end(&mut bits)?;
+                    if (bits.len() as u64) < len {
+                        return Err(PyValueError::new_err(format!(
+                            "serialized bloom filter is truncated: expected {} bytes of \
+                             filter body, got {}",
+                            len,
+                            bits.len()
+                        )));
+                    }
+                }
+                None => {
+                    reader.read_to_end(&mut bits)?;
+                }
+            }
+            // An empty BitLine would make every index computation divide by zero
+            if bits.is_empty() {
+                return Err(PyValueError::new_err(
+                    "serialized bloom filter is truncated: the filter body is empty",
+                ));
+            }
             Ok(Self {
-                bits: bits.into_boxed_slice(),
+                bits_set: count_ones(&bits),
+                bits: AlignedBytes::from_vec(bits),
             })
         }
 
@@ -499,6 +2811,79 @@ mod bitline {
         pub fn bits(&self) -> &[u8] {
             &self.bits
         }
+
+        /// Returns a BitLine of 1/factor of the length, in which each bit is
+        /// the OR of the bits at the same position in each of the factor
+        /// segments of this one. Make sure that factor divides the number of
+        /// bytes!
+        pub fn fold(&self, factor: u64) -> BitLine {
+            let segment_len = self.bits.len() / factor as usize;
+            let mut bits = AlignedBytes::zeroed(segment_len);
+            for segment in self.bits.chunks(segment_len) {
+                for (lhs, rhs) in bits.iter_mut().zip(segment) {
+                    *lhs |= rhs;
+                }
+            }
+            Self {
+                bits_set: count_ones(&bits),
+                bits,
+            }
+        }
+
+        /// Returns a mask with one bit per byte, set for the bytes that
+        /// differ from previous, and the new values of those bytes. Both
+        /// BitLines need to be the same length.
+        pub fn delta_from(&self, previous: &BitLine) -> (Vec<u8>, Vec<u8>) {
+            let mut mask = vec![0; self.bits.len().div_ceil(8)];
+            let mut values = Vec::new();
+            for (i, (&new, &old)) in self.bits.iter().zip(previous.bits.iter()).enumerate() {
+                if new != old {
+                    mask[i / 8] |= 1 << (i % 8);
+                    values.push(new);
+                }
+            }
+            (mask, values)
+        }
+
+        /// Inverse of delta_from. Make sure that mask covers every byte and
+        /// that there is a value for each bit set in it!
+        pub fn apply_delta(&mut self, mask: &[u8], values: &[u8]) {
+            let mut values = values.iter();
+            for (i, byte) in self.bits.iter_mut().enumerate() {
+                if mask[i / 8] & (1 << (i % 8)) != 0 {
+                    *byte = *values.next().unwrap();
+                }
+            }
+            self.bits_set = count_ones(&self.bits);
+        }
+    }
+
+    /// Number of bytes above which combine splits the work over threads
+    const PARALLEL_THRESHOLD: usize = 1 << 22;
+
+    /// Applies f to each pair of bytes and returns the number of set bits
+    /// in lhs afterwards. Large buffers are split into one chunk per core,
+    /// which gives the same result as the serial loop.
+    fn combine(lhs: &mut [u8], rhs: &[u8], f: impl Fn(&mut u8, u8) + Sync) -> u64 {
+        let combine_chunk = |lhs: &mut [u8], rhs: &[u8]| {
+            for (lhs, &rhs) in lhs.iter_mut().zip(rhs) {
+                f(lhs, rhs);
+            }
+            count_ones(lhs)
+        };
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        if lhs.len() < PARALLEL_THRESHOLD || threads == 1 {
+            return combine_chunk(lhs, rhs);
+        }
+        let chunk_size = lhs.len().div_ceil(threads);
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = lhs
+                .chunks_mut(chunk_size)
+                .zip(rhs.chunks(chunk_size))
+                .map(|(lhs, rhs)| scope.spawn(|| combine_chunk(lhs, rhs)))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        })
     }
 
     fn all_pairs(lhs: &BitLine, rhs: &BitLine, mut f: impl FnMut(u8, u8) -> bool) -> bool {
@@ -534,9 +2919,7 @@ mod bitline {
     }
     impl std::ops::BitAndAssign<&BitLine> for BitLine {
         fn bitand_assign(&mut self, rhs: &Self) {
-            for (lhs, rhs) in self.bits.iter_mut().zip(rhs.bits.iter()) {
-                *lhs &= rhs;
-            }
+            self.bits_set = combine(&mut self.bits, &rhs.bits, |lhs, rhs| *lhs &= rhs);
         }
     }
 
@@ -567,16 +2950,99 @@ mod bitline {
 
     impl std::ops::BitOrAssign<&BitLine> for BitLine {
         fn bitor_assign(&mut self, rhs: &Self) {
-            for (lhs, rhs) in self.bits.iter_mut().zip(rhs.bits.iter()) {
-                *lhs |= rhs;
+            self.bits_set = combine(&mut self.bits, &rhs.bits, |lhs, rhs| *lhs |= rhs);
+        }
+    }
+}
+
+/// The backing store of CountingBloom, with one saturating u8 counter per
+/// index. A counter that has reached the maximum stays there, as it can no
+/// longer tell how many of the items mapping to it have been removed.
+mod counterline {
+    use pyo3::exceptions::PyValueError;
+    use pyo3::prelude::*;
+
+    #[derive(Clone)]
+    pub struct CounterLine {
+        counters: Box<[u8]>,
+    }
+
+    impl CounterLine {
+        pub fn new(len: u64) -> PyResult<Self> {
+            match len.try_into() {
+                Ok(len) => Ok(Self {
+                    counters: vec![0; len].into_boxed_slice(),
+                }),
+                Err(_) => Err(PyValueError::new_err("too many counters")),
+            }
+        }
+
+        /// Make sure that index is less than len when calling this!
+        pub fn increment(&mut self, index: u64) {
+            let counter = &mut self.counters[index as usize];
+            *counter = counter.saturating_add(1);
+        }
+
+        /// Make sure that index is less than len when calling this!
+        pub fn decrement(&mut self, index: u64) {
+            let counter = &mut self.counters[index as usize];
+            if *counter != u8::MAX {
+                *counter = counter.saturating_sub(1);
             }
         }
+
+        /// Make sure that index is less than len when calling this!
+        pub fn get(&self, index: u64) -> u8 {
+            self.counters[index as usize]
+        }
+
+        /// Make sure that index is less than len when calling this!
+        pub fn set(&mut self, index: u64, value: u8) {
+            self.counters[index as usize] = value;
+        }
+
+        /// Decrements a counter that isn't 0, unlike decrement also when it
+        /// has reached the maximum. Make sure that index is less than len
+        /// when calling this!
+        pub fn decay(&mut self, index: u64) {
+            let counter = &mut self.counters[index as usize];
+            *counter = counter.saturating_sub(1);
+        }
+
+        /// Returns the number of counters in the CounterLine
+        pub fn len(&self) -> u64 {
+            self.counters.len() as u64
+        }
+
+        pub fn clear(&mut self) {
+            self.counters.fill(0);
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.counters.iter().all(|&counter| counter == 0)
+        }
     }
 }
 
 /// This implements a linear congruential generator that is
 /// used to distribute entropy from the hash over multiple ints.
 mod lcg {
+    /// Multiplier of the 128-bit generator, taken from the table for
+    /// modulus 2^128 in (L'Ecuyer, 1999), whose multipliers were selected
+    /// for their results in the spectral test. Being 5 mod 8 and paired with
+    /// an odd increment, it gives the generator its full period of 2^128.
+    ///
+    /// Only bits 32 to 95 of the state are output, and an LCG never carries
+    /// the high bits of its state into the low ones. So the top 32 bits of a
+    /// hash never affect its indexes, and hashes that differ only in their
+    /// upper half get poorly spread. Changing either would move the bits of
+    /// every saved filter.
+    pub const LCG_MULTIPLIER: u128 = 47026247687942121848144207491837418733;
+
+    /// Multiplier of the 64-bit generator, from the same table for modulus
+    /// 2^64
+    pub const LCG_MULTIPLIER_64: u64 = 2862933555777941757;
+
     pub struct Random {
         state: u128,
     }
@@ -585,10 +3051,7 @@ mod lcg {
         type Item = u64;
 
         fn next(&mut self) -> Option<Self::Item> {
-            self.state = self
-                .state
-                .wrapping_mul(47026247687942121848144207491837418733)
-                .wrapping_add(1);
+            self.state = self.state.wrapping_mul(LCG_MULTIPLIER).wrapping_add(1);
             Some((self.state >> 32) as Self::Item)
         }
     }
@@ -604,17 +3067,685 @@ mod lcg {
             .take(k as usize)
             .map(move |x: u64| x % len)
     }
+
+    /// Size of the blocks of a blocked filter, i.e. a typical cache line
+    pub const BLOCK_BITS: u64 = 512;
+
+    /// For blocked filters: the first random number selects a block, and the
+    /// following ones select the bits within it
+    pub fn generate_blocked_indexes(hash: i128, k: u64, len: u64) -> impl Iterator<Item = u64> {
+        blocked(distribute_entropy(hash), k, len)
+    }
+
+    fn blocked(
+        mut random: impl Iterator<Item = u64>,
+        k: u64,
+        len: u64,
+    ) -> impl Iterator<Item = u64> {
+        let block = random.next().unwrap() % (len / BLOCK_BITS) * BLOCK_BITS;
+        random
+            .take(k as usize)
+            .map(move |x: u64| block + x % BLOCK_BITS)
+    }
+
+    /// Cheaper variant of Random for hashes that only carry 64 bits of
+    /// entropy, where the 128-bit state would be pure overhead.
+    #[derive(Clone)]
+    pub struct Random64 {
+        state: u64,
+    }
+
+    impl Iterator for Random64 {
+        type Item = u64;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.state = self.state.wrapping_mul(LCG_MULTIPLIER_64).wrapping_add(1);
+            // The low bits of an LCG have short periods, so fold the high
+            // bits into them before the result gets reduced modulo len
+            Some(self.state ^ (self.state >> 32))
+        }
+    }
+
+    pub fn distribute_entropy_u64(hash: u64) -> Random64 {
+        Random64 { state: hash }
+    }
+
+    pub fn generate_indexes_u64(hash: u64, k: u64, len: u64) -> impl Iterator<Item = u64> {
+        distribute_entropy_u64(hash)
+            .take(k as usize)
+            .map(move |x: u64| x % len)
+    }
+
+    pub fn generate_blocked_indexes_u64(hash: u64, k: u64, len: u64) -> impl Iterator<Item = u64> {
+        blocked(distribute_entropy_u64(hash), k, len)
+    }
+
+    /// For count-min sketches: the k indexes each fall into their own row
+    /// of width counters, one after the other
+    pub fn generate_row_indexes(hash: i128, k: u64, width: u64) -> impl Iterator<Item = u64> {
+        rows(generate_indexes(hash, k, width), width)
+    }
+
+    pub fn generate_row_indexes_u64(hash: u64, k: u64, width: u64) -> impl Iterator<Item = u64> {
+        rows(generate_indexes_u64(hash, k, width), width)
+    }
+
+    fn rows(indexes: impl Iterator<Item = u64>, width: u64) -> impl Iterator<Item = u64> {
+        indexes
+            .zip((0..).step_by(width as usize))
+            .map(|(index, offset)| offset + index)
+    }
+}
+
+/// How the indexes of an item are spread over the filter
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum IndexMode {
+    /// Anywhere within the whole filter
+    Standard,
+    /// Within a single block of lcg::BLOCK_BITS, so that __contains__ only
+    /// touches one cache line at the cost of a slightly higher false
+    /// positive rate
+    Blocked,
+}
+
+impl IndexMode {
+    /// Blocked filters are made up of whole blocks
+    fn round_size(self, size_in_bits: u64) -> u64 {
+        match self {
+            IndexMode::Standard => size_in_bits,
+            IndexMode::Blocked => size_in_bits.div_ceil(lcg::BLOCK_BITS) * lcg::BLOCK_BITS,
+        }
+    }
+
+    /// The segments that shrink ORs together have to be whole bytes, and
+    /// for blocked filters whole blocks
+    fn fold_granularity(self) -> u64 {
+        match self {
+            IndexMode::Standard => 8,
+            IndexMode::Blocked => lcg::BLOCK_BITS,
+        }
+    }
+}
+
+/// The hash of an item, which selects the index generator: the built-in
+/// hash only carries 64 bits of entropy, so it gets the cheaper one.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum ItemHash {
+    Builtin(u64),
+    Custom(i128),
+}
+
+impl ItemHash {
+    fn for_each_index(self, mode: IndexMode, k: u64, len: u64, f: impl FnMut(u64)) {
+        use IndexMode::*;
+        match (self, mode) {
+            (ItemHash::Builtin(h), Standard) => lcg::generate_indexes_u64(h, k, len).for_each(f),
+            (ItemHash::Builtin(h), Blocked) => {
+                lcg::generate_blocked_indexes_u64(h, k, len).for_each(f)
+            }
+            (ItemHash::Custom(h), Standard) => lcg::generate_indexes(h, k, len).for_each(f),
+            (ItemHash::Custom(h), Blocked) => lcg::generate_blocked_indexes(h, k, len).for_each(f),
+        }
+    }
+
+    /// For CountMinBloom, which has a row of width counters per index
+    fn for_each_row_index(self, k: u64, width: u64, f: impl FnMut(u64)) {
+        match self {
+            ItemHash::Builtin(h) => lcg::generate_row_indexes_u64(h, k, width).for_each(f),
+            ItemHash::Custom(h) => lcg::generate_row_indexes(h, k, width).for_each(f),
+        }
+    }
+
+    /// Short-circuits as soon as f returns false
+    fn all_indexes(self, mode: IndexMode, k: u64, len: u64, f: impl FnMut(u64) -> bool) -> bool {
+        use IndexMode::*;
+        match (self, mode) {
+            (ItemHash::Builtin(h), Standard) => lcg::generate_indexes_u64(h, k, len).all(f),
+            (ItemHash::Builtin(h), Blocked) => lcg::generate_blocked_indexes_u64(h, k, len).all(f),
+            (ItemHash::Custom(h), Standard) => lcg::generate_indexes(h, k, len).all(f),
+            (ItemHash::Custom(h), Blocked) => lcg::generate_blocked_indexes(h, k, len).all(f),
+        }
+    }
+}
+
+/// The serialized form of a filter starts with this header (all integers
+/// little-endian), followed by the filter bits:
+///
+/// | offset | size | field                                      |
+/// |--------|------|--------------------------------------------|
+/// | 0      | 4    | magic, `b"RBLM"`                           |
+/// | 4      | 1    | format version, currently 1                |
+/// | 5      | 1    | flags, bit 0 set for blocked filters, bit 1 |
+/// |        |      | if a hash_id follows the header and bit 2  |
+/// |        |      | if a checksum follows the bits             |
+/// | 6      | 2    | reserved, zero                             |
+/// | 8      | 8    | number of hash functions                   |
+/// | 16     | 8    | length of the filter bits in bytes         |
+///
+/// Bit 3 of the flags is set if the tunables follow the header. The hash_id,
+/// if any, comes right after these 24 bytes as its length in bytes (2 bytes)
+/// followed by its UTF-8 encoding, and then the tunables, if any:
+///
+/// | size | field                                                   |
+/// |------|---------------------------------------------------------|
+/// | 8    | expected_items                                          |
+/// | 8    | false_positive_rate, as an IEEE 754 double              |
+/// | 4    | size of the blocks in bits, 0 for non-blocked filters   |
+/// | 1    | on_full, 0 for "ignore", 1 for "reject", 2 for "rotate" |
+/// | 8    | warn_at_fill, as an IEEE 754 double, 0 if not given     |
+///
+/// The checksum, if any, is the CRC-32 of everything before it (4 bytes).
+///
+/// Files written before the header was introduced consist of only the
+/// number of hash functions followed by the filter bits. Since that number
+/// is tiny, its first four bytes can never match the magic.
+struct Header {
+    mode: IndexMode,
+    k: u64,
+    /// Unknown for the legacy layout, where the bits extend to the end
+    body_len: Option<u64>,
+    hash_id: Option<String>,
+    /// Missing in files written before the tunables were saved
+    tunables: Option<Tunables>,
+    checksum: bool,
+}
+
+/// What a filter was designed for and how it handles being full, which
+/// don't affect which items it contains
+#[derive(Clone, Copy)]
+struct Tunables {
+    expected_items: u64,
+    false_positive_rate: f64,
+    on_full: OnFull,
+    warn_at_fill: Option<f64>,
+}
+
+impl Header {
+    const MAGIC: [u8; 4] = *b"RBLM";
+    const VERSION: u8 = 1;
+    const FLAG_BLOCKED: u8 = 1;
+    const FLAG_HASH_ID: u8 = 2;
+    const FLAG_CHECKSUM: u8 = 4;
+    const FLAG_TUNABLES: u8 = 8;
+    const LEN: usize = 24;
+    const TUNABLES_LEN: usize = 29;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; Self::LEN];
+        bytes[0..4].copy_from_slice(&Self::MAGIC);
+        bytes[4] = Self::VERSION;
+        if self.mode == IndexMode::Blocked {
+            bytes[5] |= Self::FLAG_BLOCKED;
+        }
+        bytes[8..16].copy_from_slice(&self.k.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.body_len.unwrap_or(0).to_le_bytes());
+        if self.checksum {
+            bytes[5] |= Self::FLAG_CHECKSUM;
+        }
+        if let Some(hash_id) = &self.hash_id {
+            bytes[5] |= Self::FLAG_HASH_ID;
+            // the constructor makes sure that the length fits
+            bytes.extend_from_slice(&(hash_id.len() as u16).to_le_bytes());
+            bytes.extend_from_slice(hash_id.as_bytes());
+        }
+        if let Some(tunables) = &self.tunables {
+            bytes[5] |= Self::FLAG_TUNABLES;
+            let block_bits = match self.mode {
+                IndexMode::Standard => 0,
+                IndexMode::Blocked => lcg::BLOCK_BITS as u32,
+            };
+            bytes.extend_from_slice(&tunables.expected_items.to_le_bytes());
+            bytes.extend_from_slice(&tunables.false_positive_rate.to_le_bytes());
+            bytes.extend_from_slice(&block_bits.to_le_bytes());
+            bytes.push(tunables.on_full as u8);
+            bytes.extend_from_slice(&tunables.warn_at_fill.unwrap_or(0.0).to_le_bytes());
+        }
+        bytes
+    }
+
+    fn read(reader: &mut impl Read) -> PyResult<Header> {
+        let mut bytes = [0; Self::LEN];
+        read_exact_or_truncated(reader, &mut bytes[..8], "header")?;
+        if bytes[0..4] != Self::MAGIC {
+            return Ok(Header {
+                mode: IndexMode::Standard,
+                k: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+                body_len: None,
+                hash_id: None,
+                tunables: None,
+                checksum: false,
+            });
+        }
+        read_exact_or_truncated(reader, &mut bytes[8..], "header")?;
+
+        if bytes[4] != Self::VERSION {
+            return Err(PyValueError::new_err(format!(
+                "unsupported serialization format version {}",
+                bytes[4]
+            )));
+        }
+        let flags = bytes[5];
+        let known_flags =
+            Self::FLAG_BLOCKED | Self::FLAG_HASH_ID | Self::FLAG_CHECKSUM | Self::FLAG_TUNABLES;
+        if flags & !known_flags != 0 {
+            return Err(PyValueError::new_err(format!(
+                "unsupported serialization flags {:#04x}",
+                flags
+            )));
+        }
+        let hash_id = match flags & Self::FLAG_HASH_ID {
+            0 => None,
+            _ => {
+                let mut len = [0; 2];
+                read_exact_or_truncated(reader, &mut len, "hash_id")?;
+                let mut hash_id = vec![0; u16::from_le_bytes(len) as usize];
+                read_exact_or_truncated(reader, &mut hash_id, "hash_id")?;
+                match String::from_utf8(hash_id) {
+                    Ok(hash_id) => Some(hash_id),
+                    Err(_) => {
+                        return Err(PyValueError::new_err(
+                            "serialized bloom filter has a hash_id that isn't valid UTF-8",
+                        ))
+                    }
+                }
+            }
+        };
+        let mode = match flags & Self::FLAG_BLOCKED {
+            0 => IndexMode::Standard,
+            _ => IndexMode::Blocked,
+        };
+        let tunables = match flags & Self::FLAG_TUNABLES {
+            0 => None,
+            _ => Some(Self::read_tunables(reader, mode)?),
+        };
+        Ok(Header {
+            mode,
+            k: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            body_len: Some(u64::from_le_bytes(bytes[16..24].try_into().unwrap())),
+            hash_id,
+            tunables,
+            checksum: flags & Self::FLAG_CHECKSUM != 0,
+        })
+    }
+
+    fn read_tunables(reader: &mut impl Read, mode: IndexMode) -> PyResult<Tunables> {
+        let mut bytes = [0; Self::TUNABLES_LEN];
+        read_exact_or_truncated(reader, &mut bytes, "tunables")?;
+        let expected_items = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+        let false_positive_rate = f64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        let block_bits = u32::from_le_bytes(bytes[16..20].try_into().unwrap());
+        let warn_at_fill = f64::from_le_bytes(bytes[21..29].try_into().unwrap());
+
+        // a filter whose items were placed differently can't be queried
+        let expected_block_bits = match mode {
+            IndexMode::Standard => 0,
+            IndexMode::Blocked => lcg::BLOCK_BITS as u32,
+        };
+        if block_bits != expected_block_bits {
+            return Err(PyValueError::new_err(format!(
+                "unsupported block size of {} bits",
+                block_bits
+            )));
+        }
+        let on_full = match bytes[20] {
+            0 => OnFull::Ignore,
+            1 => OnFull::Reject,
+            2 => OnFull::Rotate,
+            _ => {
+                return Err(PyValueError::new_err(
+                    "serialized bloom filter has an unknown on_full",
+                ))
+            }
+        };
+        if expected_items == 0
+            || !(false_positive_rate > 0.0 && false_positive_rate < 1.0)
+            || !(0.0..=1.0).contains(&warn_at_fill)
+        {
+            return Err(PyValueError::new_err(
+                "serialized bloom filter has invalid tunables",
+            ));
+        }
+        Ok(Tunables {
+            expected_items,
+            false_positive_rate,
+            on_full,
+            warn_at_fill: (warn_at_fill > 0.0).then_some(warn_at_fill),
+        })
+    }
+}
+
+/// Whether Bloom::read_from checks the checksum of the serialized filter
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Checksum {
+    /// Check it if there is one
+    IfPresent,
+    /// Fail if there is none
+    Required,
+    /// Read past it without checking it
+    Skip,
+}
+
+/// CRC-32 as computed by zlib.crc32, which is good enough to catch
+/// accidental corruption of a serialized filter
+mod crc32 {
+    const TABLE: [u32; 256] = table();
+
+    const fn table() -> [u32; 256] {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xEDB88320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    }
+
+    /// Continues the CRC-32 crc of some data with more data
+    pub fn update(crc: u32, data: &[u8]) -> u32 {
+        !data.iter().fold(!crc, |crc, &byte| {
+            TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+        })
+    }
+}
+
+/// Reads from a Python object with a read method, such as a file object
+struct PyReader<'py>(Bound<'py, PyAny>);
+
+impl Read for PyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let data = self.0.call_method1("read", (buf.len(),))?;
+        let data = match data.downcast::<PyBytes>() {
+            Ok(data) if data.as_bytes().len() <= buf.len() => data.as_bytes(),
+            _ => {
+                return Err(PyTypeError::new_err(
+                    "read() must return bytes of at most the size asked for",
+                )
+                .into())
+            }
+        };
+        buf[..data.len()].copy_from_slice(data);
+        Ok(data.len())
+    }
+}
+
+/// Each shard written by to_shards starts with this header (all integers
+/// little-endian), followed by its piece of the serialized filter:
+///
+/// | offset | size | field                                      |
+/// |--------|------|--------------------------------------------|
+/// | 0      | 4    | magic, `b"RBLS"`                           |
+/// | 4      | 4    | index of the shard                         |
+/// | 8      | 4    | number of shards                           |
+/// | 12     | 8    | length of the serialized filter in bytes   |
+/// | 20     | 4    | CRC-32 of the serialized filter            |
+#[derive(Clone, Copy)]
+struct ShardHeader {
+    index: u32,
+    count: u32,
+    total_len: u64,
+    checksum: u32,
+}
+
+const SHARD_MAGIC: [u8; 4] = *b"RBLS";
+const SHARD_HEADER_LEN: usize = 24;
+
+impl ShardHeader {
+    fn to_bytes(self) -> [u8; SHARD_HEADER_LEN] {
+        let mut bytes = [0; SHARD_HEADER_LEN];
+        bytes[0..4].copy_from_slice(&SHARD_MAGIC);
+        bytes[4..8].copy_from_slice(&self.index.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.count.to_le_bytes());
+        bytes[12..20].copy_from_slice(&self.total_len.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
+    }
+
+    fn read(shard: &[u8]) -> PyResult<Self> {
+        if shard.len() < SHARD_HEADER_LEN || shard[0..4] != SHARD_MAGIC {
+            return Err(PyValueError::new_err("not a bloom filter shard"));
+        }
+        Ok(ShardHeader {
+            index: u32::from_le_bytes(shard[4..8].try_into().unwrap()),
+            count: u32::from_le_bytes(shard[8..12].try_into().unwrap()),
+            total_len: u64::from_le_bytes(shard[12..20].try_into().unwrap()),
+            checksum: u32::from_le_bytes(shard[20..24].try_into().unwrap()),
+        })
+    }
 }
 
-fn hash(o: &Bound<'_, PyAny>, hash_func: &Option<Py<PyAny>>) -> PyResult<i128> {
+/// A file written by save_delta starts with this, followed by the header of
+/// the filter it was saved from, the mask of changed bytes and their values
+const DELTA_MAGIC: [u8; 4] = *b"RBLD";
+
+fn read_exact_or_truncated(reader: &mut impl Read, buf: &mut [u8], what: &str) -> PyResult<()> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        std::io::ErrorKind::UnexpectedEof => PyValueError::new_err(format!(
+            "serialized bloom filter is truncated: incomplete {}",
+            what
+        )),
+        _ => err.into(),
+    })
+}
+
+fn hash(o: &Bound<'_, PyAny>, hash_func: &Option<Py<PyAny>>) -> PyResult<ItemHash> {
     match hash_func {
         Some(hash_func) => {
             let hash_func = hash_func.bind(o.py());
-            let hash = hash_func.call1((o,))?;
-            Ok(hash.extract()?)
+            let hash = hash_func
+                .call1((o,))
+                .map_err(|err| hash_func_error(o, err))?;
+            Ok(ItemHash::Custom(custom_hash_value(&hash)?))
+        }
+        None => match o.hash() {
+            Ok(hash) => Ok(ItemHash::Builtin(hash as u64)),
+            Err(err) if err.is_instance_of::<PyTypeError>(o.py()) => {
+                let new_err = PyTypeError::new_err(format!(
+                    "object of type '{}' is not hashable by this filter's hash_func (the \
+                     built-in hash), pass a hash_func that accepts it",
+                    type_name(o)
+                ));
+                new_err.set_cause(o.py(), Some(err));
+                Err(new_err)
+            }
+            Err(err) => Err(err),
+        },
+    }
+}
+
+/// Adds a note to an exception that a custom hash_func raised for o saying
+/// where it came from, leaving the exception itself untouched. Notes need
+/// Python 3.11; on older versions, and for BaseExceptions such as
+/// KeyboardInterrupt, the exception is passed on as it is.
+fn hash_func_error(o: &Bound<'_, PyAny>, err: PyErr) -> PyErr {
+    let py = o.py();
+    if !err.is_instance_of::<PyException>(py) {
+        return err;
+    }
+    let note = format!(
+        "raised by hash_func for an object of type '{}'",
+        type_name(o)
+    );
+    let value = err.value_bound(py);
+    if value.hasattr("add_note").unwrap_or(false) {
+        // a failure to add the note shouldn't hide the original exception
+        let _ = value.call_method1("add_note", (note,));
+    }
+    err
+}
+
+/// Name of the type of o, for error messages
+fn type_name(o: &Bound<'_, PyAny>) -> String {
+    o.get_type()
+        .getattr("__qualname__")
+        .map_or_else(|_| "?".to_string(), |name| name.to_string())
+}
+
+/// Type code of a struct-style buffer format, if it is in native byte order
+fn native_format(format: &str) -> Option<char> {
+    let code = match format.as_bytes() {
+        [code] | [b'@' | b'=', code] => *code,
+        [b'<', code] if cfg!(target_endian = "little") => *code,
+        [b'>' | b'!', code] if cfg!(target_endian = "big") => *code,
+        _ => return None,
+    };
+    Some(code as char)
+}
+
+/// Interprets the value returned by a custom hash_func, which is either an
+/// integer or a bytes-like digest whose first 16 bytes are read as a
+/// little-endian integer
+fn custom_hash_value(value: &Bound<'_, PyAny>) -> PyResult<i128> {
+    if let Ok(digest) = value.downcast::<PyBytes>() {
+        digest_hash_value(digest.as_bytes())
+    } else if let Ok(digest) = value.downcast::<PyByteArray>() {
+        digest_hash_value(&digest.to_vec())
+    } else {
+        value.extract()
+    }
+}
+
+fn digest_hash_value(digest: &[u8]) -> PyResult<i128> {
+    match digest.get(..16) {
+        Some(bytes) => Ok(i128::from_le_bytes(bytes.try_into().unwrap())),
+        None => Err(PyValueError::new_err(format!(
+            "hash digests must be at least 16 bytes long, got {}",
+            digest.len()
+        ))),
+    }
+}
+
+/// Validates the constructor arguments and returns the size in bits and
+/// number of hash functions of the filter
+fn filter_parameters(expected_items: u64, false_positive_rate: f64) -> PyResult<(u64, u64)> {
+    // Check the inputs (written so that NaN is rejected as well)
+    if !(false_positive_rate > 0.0 && false_positive_rate < 1.0) {
+        return Err(PyValueError::new_err(
+            "false_positive_rate must be between 0 and 1",
+        ));
+    }
+    if expected_items == 0 {
+        return Err(PyValueError::new_err(
+            "expected_items must be greater than 0",
+        ));
+    }
+
+    // Calculate the parameters for the filter. Neither is a whole number,
+    // so the number of hash functions is rounded to the nearest one, at
+    // least 1 (with none, every item would appear present), and the size
+    // is grown to make up for that number not being the optimal one and
+    // then rounded up, so that the filter meets the rate rather than
+    // falling just short of it
+    let items = expected_items as f64;
+    let size_in_bits = optimal_size_in_bits(expected_items, false_positive_rate);
+    let k = (optimal_k(size_in_bits.ceil(), items).round() as u64).max(1);
+    let mut size_in_bits = size_in_bits
+        .max(items / max_items_for_rate(1.0, k, false_positive_rate))
+        .ceil();
+    // and in case the rounding errors of the above land on the wrong side
+    while self::false_positive_rate(size_in_bits, items, k) > false_positive_rate {
+        size_in_bits += 1.0 + size_in_bits * f64::EPSILON;
+    }
+    if size_in_bits >= u64::MAX as f64 {
+        return Err(PyValueError::new_err(
+            "expected_items and false_positive_rate require a filter too large to allocate",
+        ));
+    }
+
+    // Tiny filters with a rate near 1 would otherwise round down to no
+    // bits at all, making every index computation divide by zero
+    let size_in_bits = (size_in_bits as u64).max(MIN_SIZE_IN_BITS);
+
+    Ok((size_in_bits, k))
+}
+
+/// A number of bytes in the largest binary unit of which there's at least one
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Validates a hash_func given to a constructor; the built-in hash is
+/// represented as None
+fn custom_hash_func(hash_func: Option<Bound<'_, PyAny>>) -> PyResult<Option<Py<PyAny>>> {
+    match hash_func {
+        Some(hash_func) if !hash_func.is(builtin_hash_func(hash_func.py())?) => {
+            if !hash_func.is_callable() {
+                return Err(PyTypeError::new_err("hash_func must be callable"));
+            }
+            Ok(Some(hash_func.unbind()))
         }
-        None => Ok(o.hash()? as i128),
+        _ => Ok(None),
+    }
+}
+
+/// Number of items that, inserted with k indexes each into len bits, leave
+/// bits_set of them set on average. A full range is treated as one bit short
+/// of full, which is where the estimate stops being meaningful.
+fn estimate_items(bits_set: u64, len: u64, k: u64) -> f64 {
+    let bits_set = bits_set.min(len - 1) as f64;
+    let len = len as f64;
+    ((-bits_set / len).ln_1p() / (-1.0 / len).ln_1p() / k as f64).abs()
+}
+
+/// Number of bits needed to achieve the false positive rate for the
+/// expected number of items
+fn optimal_size_in_bits(expected_items: u64, false_positive_rate: f64) -> f64 {
+    -(expected_items as f64) * false_positive_rate.ln() / 2.0f64.ln().powi(2)
+}
+
+/// Number of hash functions that minimizes the false positive rate for a
+/// filter of the given size holding the expected number of items
+fn optimal_k(size_in_bits: f64, expected_items: f64) -> f64 {
+    (size_in_bits / expected_items) * 2.0f64.ln()
+}
+
+/// False positive rate of a filter of the given size holding the given
+/// number of items, assuming a uniform hash
+fn false_positive_rate(size_in_bits: f64, items: f64, k: u64) -> f64 {
+    (1.0 - (-(k as f64) * items / size_in_bits).exp()).powi(k as i32)
+}
+
+/// Greatest common divisor, for merge
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
     }
+    a
+}
+
+/// The expected_items and false_positive_rate of a filter that wasn't
+/// sized by them: the capacity at which its size and k are optimal
+fn assumed_design(size_in_bits: u64, k: u64) -> (u64, f64) {
+    let expected_items = ((size_in_bits as f64 * 2.0f64.ln() / k as f64) as u64).max(1);
+    (
+        expected_items,
+        false_positive_rate(size_in_bits as f64, expected_items as f64, k),
+    )
+}
+
+/// Inverse of false_positive_rate: the number of items at which a filter of
+/// the given size reaches the given false positive rate
+fn max_items_for_rate(size_in_bits: f64, k: u64, false_positive_rate: f64) -> f64 {
+    -size_in_bits / k as f64 * (-false_positive_rate.powf(1.0 / k as f64)).ln_1p()
 }
 
 fn check_compatible(a: &Bloom, b: &Bloom) -> PyResult<()> {
@@ -623,8 +3754,30 @@ fn check_compatible(a: &Bloom, b: &Bloom) -> PyResult<()> {
             "size and max false positive rate must be the same for both filters",
         ));
     }
+    check_same_hashing(a, b)
+}
+
+/// Checks that a and b put the same items at the same indexes, apart from
+/// their sizes and k
+fn check_same_hashing(a: &Bloom, b: &Bloom) -> PyResult<()> {
+    if a.mode != b.mode {
+        return Err(PyValueError::new_err(
+            "blocked and non-blocked bloom filters can't be combined",
+        ));
+    }
 
-    // now only the hash function can be different
+    // now only the hash function can be different, and filters that name
+    // theirs are taken at their word
+    if let (Some(lhs), Some(rhs)) = (&a.hash_id, &b.hash_id) {
+        if lhs != rhs {
+            return Err(PyValueError::new_err(format!(
+                "Bloom filters must have the same hash function, but their hash_ids \
+                 are {:?} and {:?}",
+                lhs, rhs
+            )));
+        }
+        return Ok(());
+    }
     match (&a.hash_func, &b.hash_func) {
         (Some(lhs), Some(rhs)) if lhs.is(rhs) => {}
         (&None, &None) => {}
@@ -652,5 +3805,10 @@ fn builtin_hash_func(py: Python<'_>) -> PyResult<&Bound<'_, PyAny>> {
 #[pymodule]
 fn rbloom(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<Bloom>()?;
+    m.add_class::<BloomSet>()?;
+    m.add_class::<UnionView>()?;
+    m.add_class::<CountingBloom>()?;
+    m.add_class::<StableBloom>()?;
+    m.add_class::<CountMinBloom>()?;
     Ok(())
 }
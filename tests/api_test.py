@@ -1,11 +1,18 @@
 #!/usr/bin/env python3
 import gc
+import gzip
+import io
 import weakref
+import zlib
 
-from rbloom import Bloom
+from rbloom import Bloom, BloomSet, CountingBloom, CountMinBloom, StableBloom, UnionView
+from array import array
 from hashlib import sha256
 from pickle import dumps
 import os
+import random
+import sys
+import warnings
 
 
 def test_bloom(bloom: Bloom):
@@ -114,8 +121,1770 @@ def circular_ref():
     assert weak_ref() is None
 
 
+def capacity_for():
+    for size_in_bits in [1000, 9600, 2**20, 512 * 2**23]:
+        for rate in [0.9, 0.1, 0.01, 0.001]:
+            expected_items, k = Bloom.capacity_for(size_in_bits, rate)
+            # the largest filter that fits, with no allocation for big ones
+            if size_in_bits <= 2**20:
+                assert Bloom(expected_items, rate).size_in_bits <= size_in_bits
+                assert Bloom(expected_items + 1, rate).size_in_bits > size_in_bits
+                assert Bloom(expected_items, rate).optimal_num_hashes() == k
+    assert Bloom.capacity_for(9600, 0.01) == (1000, 7)
+    items, k = Bloom.capacity_for(512 * 2**23, 0.001)
+    assert 2.98e8 < items < 3e8 and k == 10
+
+    for args in [(0, 0.01), (1000, 0), (1000, 1), (8, 1e-6)]:
+        try:
+            Bloom.capacity_for(*args)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def meets_design_rate():
+    import math
+
+    for expected_items in [1, 7, 100, 1000, 27_000, 10**6]:
+        for rate in [0.9, 0.5, 0.1, 0.0317, 0.01, 0.001, 1e-6, 1e-12]:
+            bloom = Bloom(expected_items, rate)
+            k = bloom.optimal_num_hashes()
+            m = bloom.size_in_bits
+            realized = (1 - math.exp(-k * expected_items / m)) ** k
+            assert realized <= rate, (expected_items, rate, realized)
+            # the number of hash functions is the nearest to the optimum
+            assert k == max(1, round(-math.log2(rate))) or expected_items < 10
+            # and the filter is no larger than needed to make up for it,
+            # give or take the rounding to whole bytes
+            if m > 8:
+                smaller = (1 - math.exp(-k * expected_items / (m - 8))) ** k
+                assert smaller > rate
+
+    bloom = Bloom(10**6, 0.01, exact=True)
+    assert bloom.size_in_bits == Bloom(10**6, 0.01).size_in_bits
+    for args, kwargs in [((1, 0.5), {}), ((100, 0.01), {'blocked': True})]:
+        Bloom(*args, **kwargs)
+        try:
+            Bloom(*args, exact=True, **kwargs)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def rate_for_memory():
+    for expected_items, rate in [(1000, 0.01), (27_000, 0.0317), (10**7, 0.001)]:
+        size = Bloom(expected_items, rate).size_in_bits
+        achievable, k = Bloom.rate_for_memory(expected_items, size)
+        assert abs(achievable - rate) / rate < 0.01
+        assert k >= 1
+
+    # more memory means a better rate
+    assert Bloom.rate_for_memory(1000, 20_000)[0] < Bloom.rate_for_memory(1000, 10_000)[0]
+
+    # the rate is one that a filter can be created with in that memory
+    for expected_items in [1, 7, 1000, 27_000]:
+        for size in [1000, 1001, 2000, 9587, 10**5]:
+            if size < expected_items:
+                continue
+            rate, k = Bloom.rate_for_memory(expected_items, size)
+            bloom = Bloom(expected_items, rate)
+            assert bloom.size_in_bits <= size and bloom.stats()['k'] == k
+
+    for args in [(0, 1000), (1000, 0), (1000, 8)]:
+        try:
+            Bloom.rate_for_memory(*args)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def degenerate_parameters():
+    # these round down to less than one bit and less than one hash function
+    for bloom in [Bloom(1, 0.99), Bloom(1, 0.999999), Bloom(1140, 0.999)]:
+        assert bloom.size_in_bits >= 8
+        bloom.add('foo')
+        assert 'foo' in bloom
+        bloom.update(range(100))
+        assert 99 in bloom
+
+    for args in [(1, float('nan')), (2**64 - 1, 1e-300)]:
+        try:
+            Bloom(*args)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def hash_quality():
+    keys = [f'key{i}' for i in range(10_000)]
+
+    good = Bloom.test_hash_quality(sha_based, keys)
+    assert abs(good['fill_ratio'] - good['expected_fill_ratio']) < 0.01
+    assert good['chi_squared'] < 2 * good['degrees_of_freedom']
+    assert good['collision_fraction'] < 0.05
+
+    bad = Bloom.test_hash_quality(lambda x: sha_based(x) % 4, keys)
+    assert bad['fill_ratio'] < good['fill_ratio'] / 10
+    assert bad['chi_squared'] > 10 * bad['degrees_of_freedom']
+    assert bad['collision_fraction'] > 0.99
+
+
+def false_positive_rate():
+    # the built-in hash and custom hashes use different index generators,
+    # both of which should deliver the design rate
+    for hash_func in [hash, sha_based]:
+        bloom = Bloom(10_000, 0.01, hash_func)
+        bloom.update(range(10_000))
+        assert all(i in bloom for i in range(10_000))
+        false_positives = sum(i in bloom for i in range(10_000, 30_000))
+        assert false_positives / 20_000 < 0.02
+
+
+def add_batch():
+    bloom = Bloom(10_000, 0.01)
+    assert bloom.add_batch(range(5_000)) is None
+    assert all(i in bloom for i in range(5_000))
+
+    # every re-added item collides, and few unique ones do
+    assert bloom.add_batch(range(5_000), count_collisions=True) == 5_000
+    assert bloom.add_batch(range(5_000, 10_000), count_collisions=True) < 100
+    assert all(i in bloom for i in range(10_000))
+
+
+def operators_with_iterables():
+    bloom = Bloom(1000, 0.01)
+    bloom |= ['foo', 'bar']
+    assert 'foo' in bloom and 'bar' in bloom
+
+    expected = Bloom(1000, 0.01)
+    expected.update(['foo', 'bar', 'baz'])
+    assert bloom | ['baz'] == expected
+    assert bloom & ['foo'] == bloom.intersection(['foo'])
+
+    bloom &= ['foo', 'qux']
+    assert 'foo' in bloom and 'bar' not in bloom
+
+    # operating on itself is a no-op rather than a borrow error
+    orig = bloom.copy()
+    bloom |= bloom
+    bloom &= bloom
+    bloom.update(bloom)
+    bloom.intersection_update(bloom)
+    assert bloom == orig
+
+    try:
+        bloom | 5
+    except TypeError:
+        pass
+    else:
+        assert False, "should have raised"
+
+
+def counting_bloom():
+    for hash_func in [hash, sha_based]:
+        bloom = CountingBloom(1000, 0.01, hash_func)
+        assert not bloom
+        bloom.add('foo')
+        bloom.add('bar')
+        bloom.add('bar')
+        assert bloom and 'foo' in bloom and 'bar' in bloom
+
+        bloom.difference_update(['foo', 'never added'])
+        assert 'foo' not in bloom
+        assert 'bar' in bloom
+
+        # 'bar' was added twice
+        bloom.difference_update(['bar'])
+        assert 'bar' in bloom
+        bloom.difference_update(['bar'])
+        assert 'bar' not in bloom
+        assert not bloom
+
+    try:
+        Bloom(1000, 0.01).difference_update(['foo'])
+    except TypeError:
+        pass
+    else:
+        assert False, "should have raised"
+
+
+def stable_bloom():
+    for hash_func in [hash, sha_based]:
+        bloom = StableBloom(1000, 0.01, hash_func)
+        assert not bloom and bloom.max_value == 3
+        assert bloom.decrements == -(-3 * bloom.num_counters // 1000)
+        try:
+            StableBloom(2**60, 0.01, hash_func, max_value=255)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+        bloom.add('foo')
+        assert bloom and 'foo' in bloom and 'bar' not in bloom
+
+        # recent items are remembered, and old ones are forgotten
+        for i in range(10_000):
+            bloom.add(i)
+        assert all(i in bloom for i in range(9_990, 10_000))
+        assert sum(i in bloom for i in range(9_900, 10_000)) >= 95
+        assert 'foo' not in bloom
+        assert sum(i in bloom for i in range(1_000)) < 50
+        # the filter doesn't fill up, however many items it gets
+        assert sum(i in bloom for i in range(-10_000, 0)) < 1_000
+
+        other = bloom.copy()
+        bloom.clear()
+        assert not bloom and 9_999 in other
+
+    bloom = StableBloom(1000, 0.01, decrements=1, max_value=255)
+    assert bloom.decrements == 1 and bloom.max_value == 255
+    for i in range(10_000):
+        bloom.add(i)
+    assert all(i in bloom for i in range(10_000))
+
+    for kwargs in [{'decrements': 0}, {'max_value': 0}]:
+        try:
+            StableBloom(1000, 0.01, **kwargs)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def count_min_bloom():
+    for hash_func in [hash, sha_based]:
+        bloom = CountMinBloom(1000, 0.01, hash_func)
+        assert not bloom and bloom.hash_func == hash_func
+        assert bloom.rows == 7 and bloom.num_counters % 7 == 0
+        assert bloom.count('foo') == 0 and 'foo' not in bloom
+
+        for i in range(1000):
+            bloom.add(i, count=i % 10 + 1)
+        bloom.add('foo')
+        bloom.add('foo', 41)
+        assert bloom and 'foo' in bloom
+        assert bloom.count('foo') >= 42
+        assert all(bloom.count(i) >= i % 10 + 1 for i in range(1000))
+        assert sum(bloom.count(i) == i % 10 + 1 for i in range(1000)) > 900
+        assert sum(i in bloom for i in range(-10_000, 0)) < 200
+
+        other = bloom.copy()
+        bloom.clear()
+        assert not bloom and bloom.count('foo') == 0 and other.count('foo') >= 42
+
+    bloom = CountMinBloom(10, 0.01)
+    bloom.add('foo', 2**32 - 1)
+    bloom.add('foo')
+    assert bloom.count('foo') == 2**32 - 1
+    assert repr(bloom).startswith('<CountMinBloom num_counters=')
+
+
+def precomputed_hashes():
+    for hash_func in [hash, sha_based]:
+        bloom = Bloom(1000, 0.01, hash_func)
+        bloom.add_hash(hash_func('foo'))
+        assert 'foo' in bloom
+        bloom.add('bar')
+        assert bloom.contains_hash(hash_func('bar'))
+        assert not bloom.contains_hash(hash_func('baz'))
+
+    try:
+        Bloom(1000, 0.01).add_hash(2**100)
+    except ValueError:
+        pass
+    else:
+        assert False, "should have raised"
+
+
+def bit_inspection():
+    bloom = Bloom(1000, 0.01)
+    assert not any(bloom[i] for i in range(bloom.size_in_bits))
+    bloom.add('foo')
+    assert 1 <= sum(bloom[i] for i in range(bloom.size_in_bits)) <= 7
+
+    for index in [-1, bloom.size_in_bits]:
+        try:
+            bloom[index]
+        except IndexError:
+            pass
+        else:
+            assert False, "should have raised"
+
+    # indexing doesn't make the filter iterable over its bits, and there is
+    # no buffer protocol to read them through with abi3-py37
+    for consume in [iter, list, set, bytes, memoryview]:
+        try:
+            consume(bloom)
+        except TypeError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def indices_for():
+    for kwargs in [{}, {'blocked': True}]:
+        for hash_func in [hash, sha_based]:
+            bloom = Bloom(1000, 0.01, hash_func, **kwargs)
+            indices = bloom.indices_for('foo')
+            assert len(indices) == bloom.stats()['k']
+            assert indices == bloom.indices_for('foo')
+            assert not bloom
+            bloom.add('foo')
+            assert {i for i in range(bloom.size_in_bits) if bloom[i]} == set(indices)
+            if kwargs:
+                assert len({i // 512 for i in indices}) == 1
+            assert bloom.indices_for('bar') != indices
+
+    try:
+        Bloom(1000, 0.01).indices_for([])
+    except TypeError:
+        pass
+    else:
+        assert False, "should have raised"
+
+
+def truncated_bytes():
+    k_only = Bloom(1000, 0.01, sha_based).save_bytes()[:8]
+    for data in [b'', b'\x01\x02\x03', k_only]:
+        try:
+            Bloom.load_bytes(data, sha_based)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def blocked():
+    for hash_func in [hash, sha_based]:
+        bloom = Bloom(10_000, 0.01, hash_func, blocked=True)
+        assert bloom.blocked
+        assert bloom.size_in_bits % 512 == 0
+        bloom.update(range(10_000))
+        assert all(i in bloom for i in range(10_000))
+        false_positives = sum(i in bloom for i in range(10_000, 30_000))
+        assert false_positives / 20_000 < 0.03
+
+        # all bits of an item lie within one block
+        single = Bloom(10_000, 0.01, hash_func, blocked=True)
+        single.add('foo')
+        set_bits = [i for i in range(single.size_in_bits) if single[i]]
+        assert set_bits[-1] // 512 == set_bits[0] // 512
+
+    assert not Bloom(10_000, 0.01).blocked
+    try:
+        Bloom(10_000, 0.01, blocked=True) | Bloom(10_000, 0.01)
+    except ValueError:
+        pass
+    else:
+        assert False, "should have raised"
+
+    bloom = Bloom(1000, 0.01, sha_based, blocked=True)
+    bloom.update(range(1000))
+    loaded = Bloom.load_bytes(bloom.save_bytes(), sha_based)
+    assert loaded.blocked
+    assert loaded == bloom
+
+
+def legacy_format():
+    # before the header was introduced, files held only k and the bits
+    bloom = Bloom(1000, 0.01, sha_based)
+    bloom.update(range(1000))
+    data = bloom.save_bytes()
+    assert data[:4] == b'RBLM'
+    k, body = data[8:16], data[-(bloom.size_in_bits // 8):]
+    loaded = Bloom.load_bytes(k + body, sha_based)
+    assert loaded == bloom
+
+    for corrupt in [data[:-1], data + b'\x00', data[:5] + b'\xff' + data[6:]]:
+        try:
+            Bloom.load_bytes(corrupt, sha_based)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def merge():
+    for blocked in [False, True]:
+        large = Bloom(10_000, 0.01, blocked=blocked)
+        large.update(range(500))
+        small = Bloom(10_000, 0.01, blocked=blocked)
+        small.shrink(2)
+        small.update(range(500, 600))
+
+        merged = large.merge(small)
+        assert merged.size_in_bits == small.size_in_bits
+        assert merged.blocked == blocked
+        assert all(i in merged for i in range(600))
+        assert small.merge(large) == merged
+        folded = large.copy()
+        folded.shrink(2)
+        assert merged == folded | small
+        assert large.size_in_bits == 2 * small.size_in_bits and 0 not in small
+
+    # neither size divides the other
+    a = Bloom.with_size(8 * 6, 3)
+    b = Bloom.with_size(8 * 4, 3)
+    a.add('foo')
+    b.add('bar')
+    merged = a.merge(b)
+    assert merged.size_in_bits == 16 and 'foo' in merged and 'bar' in merged
+    assert b.merge(a) == merged
+
+    for other, error in [(Bloom.with_size(8 * 6, 4), ValueError),
+                         (Bloom.with_size(8 * 6, 3, sha_based), ValueError),
+                         ([1, 2], TypeError)]:
+        try:
+            Bloom.with_size(8 * 4, 3).merge(other)
+        except error:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def hash_errors():
+    for filter in [Bloom(100, 0.01), CountingBloom(100, 0.01)]:
+        try:
+            filter.add([1, 2])
+        except TypeError as e:
+            assert "'list'" in str(e) and 'hash_func' in str(e)
+            assert isinstance(e.__cause__, TypeError)
+        else:
+            assert False, "should have raised"
+
+    def picky(obj):
+        if obj == 'bad':
+            raise ValueError('no bad items')
+        return hash(obj)
+
+    bloom = Bloom(100, 0.01, picky)
+    for item in ['bad', {1}]:
+        try:
+            bloom.add(item)
+        except (ValueError, TypeError) as e:
+            if sys.version_info >= (3, 11):
+                assert e.__notes__ == [
+                    f"raised by hash_func for an object of type "
+                    f"'{type(item).__name__}'"]
+        else:
+            assert False, "should have raised"
+
+    # the exception the hash_func raised is the one that propagates
+    class CodedError(Exception):
+        def __init__(self, code):
+            super().__init__(code)
+            self.code = code
+
+    def coded(obj):
+        raise CodedError(42)
+
+    def lookup(obj):
+        return {}[obj]
+
+    for func, cls, check in [(coded, CodedError, lambda e: e.code == 42),
+                             (lookup, KeyError, lambda e: e.args == ('k',))]:
+        try:
+            Bloom(100, 0.01, func).add('k')
+        except cls as e:
+            assert type(e) is cls and check(e)
+        else:
+            assert False, "should have raised"
+
+    try:
+        'bad' in bloom
+    except ValueError as e:
+        assert str(e) == 'no bad items'
+    else:
+        assert False, "should have raised"
+
+    # a hash_func returning something unusable is reported by rbloom
+    try:
+        Bloom(100, 0.01, lambda obj: 'foo').add(1)
+    except TypeError as e:
+        assert not getattr(e, '__notes__', None)
+    else:
+        assert False, "should have raised"
+
+    def interrupted(obj):
+        raise KeyboardInterrupt
+    try:
+        Bloom(100, 0.01, interrupted).add(1)
+    except KeyboardInterrupt as e:
+        assert e.__cause__ is None
+    else:
+        assert False, "should have raised"
+
+
+def with_size():
+    bloom = Bloom.with_size(1024, 3)
+    assert bloom.size_in_bits == 1024 and bloom.stats()['k'] == 3
+    assert not bloom and not bloom.blocked and bloom.hash_func is hash
+    bloom.add('foo')
+    assert 'foo' in bloom and len(set(bloom.indices_for('foo'))) <= 3
+    # the capacity at which 3 hash functions are optimal for 1024 bits
+    assert bloom.expected_items == 236
+    assert 0.1 < bloom.false_positive_rate < 0.15
+
+    # matches a filter made by the constructor, so they can be combined
+    other = Bloom(1000, 0.01, sha_based)
+    same = Bloom.with_size(other.size_in_bits, other.stats()['k'], sha_based)
+    other.add('foo')
+    same.add('foo')
+    assert same == other and same | other == other
+
+    for size, k in [(0, 1), (13, 1), (8, 0), (-8, 1)]:
+        try:
+            Bloom.with_size(size, k)
+        except (ValueError, OverflowError):
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def tunables_round_trip():
+    keys = list(range(-2000, 2000)) + [str(i) for i in range(1000)]
+    for is_blocked in [False, True]:
+        for kwargs in [{}, {'hash_id': 'sha'}, {'warn_at_fill': 0.9, 'on_full': 'reject'},
+                       {'on_full': 'rotate'}]:
+            bloom = Bloom(1000, 0.001, sha_based, blocked=is_blocked, **kwargs)
+            bloom.update(range(0, 2000, 3))
+            for loaded in [Bloom.load_bytes(bloom.save_bytes(), sha_based),
+                           Bloom.from_shards(bloom.to_shards(3), sha_based)]:
+                assert [key in loaded for key in keys] == [key in bloom for key in keys]
+                for attr in ['blocked', 'size_in_bits', 'expected_items',
+                             'false_positive_rate', 'hash_id', 'on_full']:
+                    assert getattr(loaded, attr) == getattr(bloom, attr), attr
+                assert loaded.optimal_num_hashes() == bloom.optimal_num_hashes()
+                assert loaded == bloom
+
+    # warn_at_fill is restored as well
+    bloom = Bloom(1000, 0.01, sha_based, warn_at_fill=0.25)
+    loaded = Bloom.load_bytes(bloom.save_bytes(), sha_based)
+    with warnings.catch_warnings(record=True) as caught:
+        warnings.simplefilter('always')
+        loaded.update(range(1000))
+    assert len(caught) == 1
+
+    # the tunables come right after the hash_id
+    bloom = Bloom(1000, 0.01, sha_based, blocked=True)
+    data = bytearray(bloom.save_bytes())
+    assert data[5] == 1 | 8
+    assert int.from_bytes(data[24:32], 'little') == 1000
+    assert int.from_bytes(data[40:44], 'little') == 512
+    for offset, value in [(40, 256), (44, 3), (24, 0), (32, 0)]:
+        corrupt = bytearray(data)
+        corrupt[offset:offset + 4] = value.to_bytes(4, 'little')
+        if offset == 32:
+            corrupt[32:40] = bytes(8)
+        try:
+            Bloom.load_bytes(bytes(corrupt), sha_based)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def approx_items():
+    for is_blocked in [False, True]:
+        bloom = Bloom(10_000, 0.01, blocked=is_blocked)
+        inserted = 0
+        for count in [100, 1_000, 10_000, 40_000, 80_000]:
+            bloom.update(range(inserted, count))
+            inserted = count
+            assert abs(bloom.approx_items - count) / count < 0.05
+
+        # a saturated filter gives a finite estimate that stops growing
+        bloom.update(range(80_000, 1_000_000))
+        saturated = bloom.approx_items
+        bloom.update(range(1_000_000, 2_000_000))
+        assert bloom.approx_items == saturated < 1_000_000
+
+
+def inspect_bytes():
+    bloom = Bloom(1000, 0.01, sha_based, blocked=True, hash_id='sha',
+                  warn_at_fill=0.5, on_full='rotate')
+    bloom.add('foo')
+    data = bloom.save_bytes()
+    info = Bloom.inspect_bytes(data)
+    assert info == {
+        'format_version': 1,
+        'k': bloom.stats()['k'],
+        'size_in_bits': bloom.size_in_bits,
+        'blocked': True,
+        'hash_id': 'sha',
+        'checksum': False,
+        'expected_items': 1000,
+        'false_positive_rate': bloom.false_positive_rate,
+        'on_full': 'rotate',
+        'warn_at_fill': 0.5,
+    }
+    # the header is enough
+    assert Bloom.inspect_bytes(data[:-(bloom.size_in_bits // 8)]) == info
+
+    f = io.BytesIO()
+    Bloom(100, 0.1, sha_based).save_to(f)
+    info = Bloom.inspect_bytes(f.getvalue())
+    assert info['checksum'] and not info['blocked'] and info['hash_id'] is None
+    assert info['warn_at_fill'] is None and info['on_full'] == 'ignore'
+
+    legacy = (3).to_bytes(8, 'little') + bytes(8)
+    for data in [b'', legacy, b'RBLM', b'RBLM\x02' + bytes(19), data[:30]]:
+        try:
+            Bloom.inspect_bytes(data)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def journal():
+    i = 0
+    while os.path.exists(f'test{i}.bloom') or os.path.exists(f'test{i}.journal'):
+        i += 1
+    snapshot, journal = f'test{i}.bloom', f'test{i}.journal'
+    try:
+        bloom = Bloom(10_000, 0.01, sha_based, journal_path=journal,
+                      on_full='reject')
+        bloom.checkpoint(snapshot)
+        assert os.path.getsize(journal) == 0
+        bloom.add('foo')
+        bloom.add_all('bar', 'baz')
+        bloom.add_hash(sha_based('qux'))
+        bloom.add_batch(range(100))
+        bloom.update(range(100, 200))
+        assert os.path.getsize(journal) == 16 * 204
+        # copies don't write to the journal
+        bloom.copy().add('quux')
+        assert os.path.getsize(journal) == 16 * 204
+
+        replayed = Bloom.replay(snapshot, journal, sha_based)
+        assert replayed == bloom and replayed.on_full == 'reject'
+
+        bloom.checkpoint(snapshot)
+        assert os.path.getsize(journal) == 0
+        assert not os.path.exists(snapshot + '.tmp')
+        bloom.add('quux')
+        # a record cut short by a crash is ignored, and dropped when the
+        # journal is opened again
+        with open(journal, 'ab') as f:
+            f.write(b'\x01\x02\x03')
+        replayed = Bloom.replay(snapshot, journal, sha_based)
+        assert replayed == bloom and 'quux' in replayed
+        assert os.path.getsize(journal) == 16
+        # the replayed filter goes on appending to the journal
+        replayed.add('corge')
+        assert Bloom.replay(snapshot, journal, sha_based) == replayed
+
+        try:
+            Bloom(10_000, 0.01, sha_based).checkpoint(snapshot)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+        try:
+            Bloom(10_000, 0.01, journal_path=journal)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+        # changes the journal can't record checkpoint the filter
+        other = Bloom(10_000, 0.01, sha_based)
+        other.add('grault')
+        replayed |= other
+        assert os.path.getsize(journal) == 0
+        assert 'grault' in Bloom.replay(snapshot, journal, sha_based)
+        replayed &= other
+        replayed.shrink(2)
+        assert Bloom.replay(snapshot, journal, sha_based) == replayed
+        replayed.reconfigure(1000, 0.01)
+        assert Bloom.replay(snapshot, journal, sha_based) == replayed
+        replayed.add('a')
+        replayed.clear()
+        assert 'a' not in Bloom.replay(snapshot, journal, sha_based)
+        os.remove(journal)
+        rotating = Bloom(20, 0.01, sha_based, journal_path=journal,
+                         on_full='rotate')
+        rotating.checkpoint(snapshot)
+        with warnings.catch_warnings():
+            warnings.simplefilter('ignore')
+            rotating.update(range(40))
+        assert rotating.approx_items < 30
+        assert Bloom.replay(snapshot, journal, sha_based) == rotating
+        # ...unless it hasn't been checkpointed yet
+        os.remove(journal)
+        fresh = Bloom(10_000, 0.01, sha_based, journal_path=journal)
+        for change in [lambda: fresh.__ior__(other),
+                       lambda: fresh.update(other),
+                       lambda: fresh.__iand__(other),
+                       lambda: fresh.intersection_update(['grault']),
+                       lambda: fresh.shrink(2),
+                       lambda: fresh.reconfigure(1000, 0.01),
+                       fresh.clear]:
+            try:
+                change()
+            except ValueError as e:
+                assert 'checkpoint' in str(e)
+            else:
+                assert False, "should have raised"
+        fresh.update(['grault'])
+        os.remove(journal)
+        full = Bloom(1, 0.01, sha_based, journal_path=journal,
+                     on_full='rotate')
+        try:
+            full.update(range(10))
+        except ValueError as e:
+            assert 'checkpoint' in str(e)
+        else:
+            assert False, "should have raised"
+        # copies have no journal
+        assert 'grault' in fresh.copy() & other
+    finally:
+        for filename in [snapshot, journal, snapshot + '.tmp']:
+            if os.path.exists(filename):
+                os.remove(filename)
+
+
+def delta():
+    bloom = Bloom(10_000, 0.01, sha_based)
+    bloom.update(range(1_000))
+    previous = bloom.copy()
+    bloom.update(range(1_000, 1_100))
+
+    i = 0
+    while os.path.exists(f'test{i}.bloom') or os.path.exists(f'test{i}.delta'):
+        i += 1
+    base, delta = f'test{i}.bloom', f'test{i}.delta'
+    try:
+        previous.save(base)
+        bloom.save_delta(delta, previous)
+        # the delta holds a mask of changed bytes and only those bytes
+        assert os.path.getsize(delta) < os.path.getsize(base) / 4
+        assert Bloom.apply_delta(base, delta, sha_based) == bloom
+
+        # a delta doesn't apply to a filter with different parameters
+        Bloom(10_000, 0.02, sha_based).save(base)
+        try:
+            Bloom.apply_delta(base, delta, sha_based)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+        previous.save(base)
+        with open(delta, 'rb') as f:
+            data = f.read()
+        for corrupt in [data[:-1], data + b'\x00', b'RBLM' + data[4:]]:
+            with open(delta, 'wb') as f:
+                f.write(corrupt)
+            try:
+                Bloom.apply_delta(base, delta, sha_based)
+            except ValueError:
+                pass
+            else:
+                assert False, "should have raised"
+    finally:
+        for filename in [base, delta]:
+            if os.path.exists(filename):
+                os.remove(filename)
+
+    try:
+        Bloom(1000, 0.01, sha_based).save_delta('unused', Bloom(1000, 0.02, sha_based))
+    except ValueError:
+        pass
+    else:
+        assert False, "should have raised"
+
+
+def index_uniformity():
+    # with well-distributed hashes, both index generators should spread the
+    # indexes evenly, whatever the size of the filter and number of indexes
+    rng = random.Random(0)
+    for hash_func, bits in [(lambda x: x, 128), (hash, 60)]:
+        for rate in [0.5, 0.01, 1e-6]:
+            seeds = [rng.getrandbits(bits) - 2**(bits - 1) for _ in range(20_000)]
+            quality = Bloom.test_hash_quality(hash_func, seeds, rate)
+            # about six standard deviations above the mean
+            dof = quality['degrees_of_freedom']
+            assert quality['chi_squared'] < dof + 6 * (2 * dof)**0.5
+            assert abs(quality['fill_ratio'] - quality['expected_fill_ratio']) < 0.01
+
+    # a multiplier with a poor lattice structure correlates the indexes of
+    # neighbouring seeds, which shows in the false positive rate
+    for hash_func in [lambda x: x, lambda x: x << 32, hash]:
+        for rate in [0.01, 0.0001]:
+            bloom = Bloom(20_000, rate, hash_func)
+            bloom.update(range(20_000))
+            false_positives = sum(i in bloom for i in range(20_000, 220_000))
+            assert false_positives / 200_000 < 2 * rate
+
+
+def retune():
+    bloom = Bloom(1000, 0.01)
+    initial = bloom.optimal_num_hashes()
+    bloom.update(range(1000))
+    assert abs(bloom.optimal_num_hashes() - initial) <= 1
+    with warnings.catch_warnings():
+        warnings.simplefilter('error')
+        bloom.retune()
+
+    # an overfilled filter needs fewer hash functions
+    bloom.update(range(1000, 5000))
+    assert bloom.optimal_num_hashes() < initial
+    with warnings.catch_warnings(record=True) as caught:
+        warnings.simplefilter('always')
+        bloom.retune()
+    assert len(caught) == 1 and caught[0].category is RuntimeWarning
+
+
+def digest_hashes():
+    def digest(obj):
+        return sha256(dumps(obj)).digest()
+
+    def digest_as_int(obj):
+        return int.from_bytes(digest(obj)[:16], 'little', signed=True)
+
+    for hash_func in [digest, lambda obj: bytearray(digest(obj))]:
+        bloom = Bloom(1000, 0.01, hash_func)
+        bloom.update(range(500))
+        assert all(i in bloom for i in range(500))
+
+        # the first 16 bytes are read as a little-endian integer
+        as_ints = Bloom(1000, 0.01, digest_as_int)
+        as_ints.update(range(500))
+        assert as_ints.save_bytes() == bloom.save_bytes()
+
+        bloom.add_hash(digest('foo'))
+        assert 'foo' in bloom and bloom.contains_hash(digest('foo'))
+
+    try:
+        Bloom(1000, 0.01, lambda obj: digest(obj)[:15]).add('foo')
+    except ValueError:
+        pass
+    else:
+        assert False, "should have raised"
+
+
+def from_hashes():
+    rng = random.Random(0)
+    values = [rng.getrandbits(64) for _ in range(1000)]
+    for hash_func in [hash, sha_based]:
+        # numpy int64 and uint64 arrays hand out the same kind of buffer
+        for typecode, hashes in [('q', [v - 2**63 for v in values]), ('Q', values)]:
+            if hash_func is hash and typecode == 'Q':
+                continue
+            bloom = Bloom.from_hashes(array(typecode, hashes), 1000, 0.01, hash_func)
+            expected = Bloom(1000, 0.01, hash_func)
+            for h in hashes:
+                expected.add_hash(h)
+            assert bloom == expected
+            assert bloom.hash_func is hash_func
+
+    assert Bloom.from_hashes(array('q', [1]), 1000, 0.01, blocked=True).blocked
+
+    for hashes in [array('Q', [2**63]), array('i', [1]), b'12345678']:
+        try:
+            Bloom.from_hashes(hashes, 1000, 0.01)
+        except (TypeError, ValueError):
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def iter_chunks():
+    bloom = Bloom(1000, 0.01, sha_based)
+    bloom.update(range(1000))
+    data = bloom.save_bytes()
+    for chunk_size in [1, 7, 24, 25, len(data), 1 << 20]:
+        chunks = list(bloom.iter_chunks(chunk_size))
+        assert b''.join(chunks) == data
+        assert all(len(chunk) == chunk_size for chunk in chunks[:-1])
+        assert 0 < len(chunks[-1]) <= chunk_size
+    assert b''.join(bloom.iter_chunks()) == data
+
+    for bloom, chunk_size in [(Bloom(1000, 0.01), 10), (bloom, 0)]:
+        try:
+            bloom.iter_chunks(chunk_size)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def shrink():
+    for is_blocked, granularity in [(False, 8), (True, 512)]:
+        # find a capacity for which the size can be folded by 4
+        expected_items = 100_000
+        while True:
+            bloom = Bloom(expected_items, 0.01, sha_based, blocked=is_blocked)
+            if bloom.size_in_bits % (4 * granularity) == 0:
+                break
+            expected_items += 1
+        assert bloom.expected_items == expected_items
+        assert bloom.false_positive_rate == 0.01
+        bloom.update(range(1_000))
+        size = bloom.size_in_bits
+        factor = 4
+        bloom.shrink(factor)
+        assert bloom.size_in_bits == size // factor
+        assert all(i in bloom for i in range(1_000))
+        assert bloom.false_positive_rate > 0.01
+        bloom.shrink(1)
+        assert bloom.size_in_bits == size // factor
+
+    bloom = Bloom(1001, 0.01)
+    for factor in [0, 3, bloom.size_in_bits]:
+        try:
+            bloom.shrink(factor)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+    # loaded filters assume the capacity at which their k is optimal
+    loaded = Bloom.load_bytes(Bloom(1000, 0.01, sha_based).save_bytes(), sha_based)
+    assert 900 < loaded.expected_items < 1200
+    assert 0.005 < loaded.false_positive_rate < 0.02
+
+
+def contains_fraction():
+    for hash_func in [hash, sha_based]:
+        bloom = Bloom(10_000, 0.01, hash_func)
+        bloom.update(range(1_000))
+        assert bloom.contains_fraction(range(1_000)) == 1.0
+        assert bloom.contains_fraction(range(500, 1_500)) >= 0.5
+        assert bloom.contains_fraction(range(500, 1_500)) < 0.52
+        assert bloom.contains_fraction(iter(['never added'])) in (0.0, 1.0)
+
+        try:
+            bloom.contains_fraction([])
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def fingerprint():
+    bloom = Bloom(1000, 0.01, sha_based)
+    bloom.update(range(100))
+    assert len(bloom.fingerprint()) == 32
+
+    # the same contents, built in a different way and with a different
+    # but equivalent hash function object
+    other = Bloom.load_bytes(bloom.save_bytes(), lambda x: sha_based(x))
+    for i in reversed(range(100)):
+        other.add(i)
+    assert other.fingerprint() == bloom.fingerprint()
+
+    data = bloom.save_bytes()
+    expected = sha256(data[8:16] + b'\x00' + data[-(bloom.size_in_bits // 8):]).digest()
+    assert bloom.fingerprint() == expected
+
+    other.add('foo')
+    assert other.fingerprint() != bloom.fingerprint()
+    assert Bloom(1000, 0.01).fingerprint() == Bloom(1000, 0.01).fingerprint()
+    assert Bloom(1000, 0.01).fingerprint() != Bloom(1000, 0.02).fingerprint()
+
+
+def add_lines():
+    i = 0
+    while os.path.exists(f'test{i}.txt'):
+        i += 1
+    filename = f'test{i}.txt'
+    try:
+        with open(filename, 'wb') as f:
+            f.write('foo\nbär\r\n\nbaz\n'.encode())
+        for hash_func in [hash, sha_based]:
+            bloom = Bloom(1000, 0.01, hash_func)
+            bloom.add_lines(filename)
+            assert 'foo' in bloom and 'bär' in bloom and 'baz' in bloom
+            # the trailing newline doesn't add an empty line
+            expected = Bloom(1000, 0.01, hash_func)
+            expected.update(['foo', 'bär', '', 'baz'])
+            assert bloom == expected
+
+            skipped = Bloom(1000, 0.01, hash_func)
+            skipped.add_lines(filename, skip_empty=True)
+            assert '' not in skipped and 'baz' in skipped
+
+        with open(filename, 'wb') as f:
+            f.write('foo\nbär'.encode('latin-1'))
+        bloom = Bloom(1000, 0.01)
+        bloom.add_lines(filename, 'latin-1')
+        assert 'foo' in bloom and 'bär' in bloom
+        try:
+            bloom.add_lines(filename)
+        except UnicodeDecodeError:
+            pass
+        else:
+            assert False, "should have raised"
+
+        # lines are split before decoding, which needs ASCII to stay ASCII
+        with open(filename, 'w', encoding='utf-16') as f:
+            f.write('foo\nbar\n')
+        for encoding, error in [('utf-16', ValueError), ('utf-7', ValueError),
+                                ('cp500', ValueError), ('no-such-codec', LookupError)]:
+            try:
+                bloom.add_lines(filename, encoding)
+            except error:
+                pass
+            else:
+                assert False, "should have raised"
+    finally:
+        os.remove(filename)
+
+
+def reconfigure():
+    for is_blocked in [False, True]:
+        for expected_items, rate in [(100, 0.01), (100_000, 0.001), (10, 0.1)]:
+            bloom = Bloom(10_000, 0.01, sha_based, blocked=is_blocked)
+            bloom.update(range(1_000))
+            bloom.reconfigure(expected_items, rate)
+            fresh = Bloom(expected_items, rate, sha_based, blocked=is_blocked)
+            assert bloom.size_in_bits == fresh.size_in_bits
+            assert bloom.expected_items == expected_items
+            assert bloom.false_positive_rate == rate
+            assert not bloom and bloom.hash_func is sha_based
+            bloom.update(range(expected_items))
+            fresh.update(range(expected_items))
+            assert bloom == fresh
+
+    bloom = Bloom(1000, 0.01)
+    for args in [(0, 0.01), (1000, 1.5), (1000, float('nan'))]:
+        try:
+            bloom.reconfigure(*args)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+    # an iterator over the serialized filter notices the size changing
+    bloom = Bloom(100_000, 0.01, sha_based)
+    chunks = bloom.iter_chunks(1000)
+    next(chunks)
+    bloom.reconfigure(10, 0.01)
+    try:
+        next(chunks)
+    except RuntimeError:
+        pass
+    else:
+        assert False, "should have raised"
+
+
+def add_all():
+    for hash_func in [hash, sha_based]:
+        bloom = Bloom(1000, 0.01, hash_func)
+        bloom.add_all('foo', 'bar', (1, 2), 3)
+        expected = Bloom(1000, 0.01, hash_func)
+        for item in ['foo', 'bar', (1, 2), 3]:
+            expected.add(item)
+        assert bloom == expected
+        assert 'f' not in bloom and 1 not in bloom
+
+        # update iterates over each argument instead
+        characters = Bloom(1000, 0.01, hash_func)
+        characters.update('foo', 'bar')
+        assert 'f' in characters and 'foo' not in characters
+
+        bloom.add_all()
+        assert bloom == expected
+
+    bloom = Bloom(10, 0.01, on_full='reject')
+    try:
+        bloom.add_all(*range(100))
+    except RuntimeError:
+        pass
+    else:
+        assert False, "should have raised"
+    assert 0 in bloom and 99 not in bloom
+
+    try:
+        Bloom(10, 0.01).add_all('foo', [])
+    except TypeError:
+        pass
+    else:
+        assert False, "should have raised"
+
+
+def exclusions():
+    bloom = Bloom(100, 0.01)
+    bloom.update(range(100))
+    # a key that was never added but whose bits happen to be set
+    fp = next(i for i in range(100, 10**6) if i in bloom)
+    bloom.add_exclusion(fp)
+    bloom.add_exclusion(fp)
+    assert fp not in bloom
+    assert all(i in bloom for i in range(100))
+    assert bloom.contains_hash(hash(fp))
+    # everything that looks up keys rather than hashes honors exclusions
+    assert bloom.measured_false_positive_rate([fp]) == 0.0
+    assert bloom.contains_fraction([fp, 1]) == 0.5
+    assert fp not in UnionView([bloom]) and 1 in UnionView([bloom])
+    other = Bloom(100, 0.01)
+    other.add(fp)
+    assert fp in UnionView([bloom, other])
+    assert BloomSet([bloom, other]).which_contain(fp) == [1]
+    assert BloomSet([bloom, other]).which_contain(1) == [0]
+
+    # only equal keys are excluded, even if they collide
+    bloom = Bloom(100, 0.01, lambda o: 1)
+    bloom.add("a")
+    bloom.add_exclusion("b")
+    assert "b" not in bloom and "a" in bloom and "c" in bloom
+
+    # keys that compare equal count as the same key, even across types
+    bloom = Bloom(100, 0.01)
+    bloom.add_all(1, 2)
+    bloom.add_exclusion(1.0)
+    assert 1 not in bloom and 2 in bloom
+
+    copy = bloom.copy()
+    assert 1 not in copy and 1 not in bloom | Bloom(100, 0.01)
+    bloom.clear()
+    bloom.add(1)
+    assert 1 in bloom and 1 not in copy
+    # a reconfigured filter starts over, without the old exclusions
+    bloom.add_exclusion('x')
+    bloom.reconfigure(1000, 0.01)
+    bloom.add('x')
+    assert 'x' in bloom
+
+    # unhashable keys are excluded through a custom hash_func
+    bloom = Bloom(100, 0.01, lambda o: o[0])
+    bloom.add([1, 2])
+    bloom.add_exclusion([1, 3])
+    assert [1, 2] in bloom and [1, 3] not in bloom
+
+    try:
+        Bloom(100, 0.01).add_exclusion([])
+    except TypeError:
+        pass
+    else:
+        assert False, "should have raised"
+
+
+def empty_like():
+    for kwargs in [{}, {'blocked': True}, {'hash_id': 'sha', 'on_full': 'rotate'},
+                   {'warn_at_fill': 0.5, 'on_full': 'reject'}]:
+        hash_func = sha_based if kwargs else hash
+        bloom = Bloom(1000, 0.001, hash_func, **kwargs)
+        bloom.update(range(100))
+        empty = Bloom.empty_like(bloom)
+        assert not empty and 1 not in empty
+        for attr in ['size_in_bits', 'blocked', 'expected_items',
+                     'false_positive_rate', 'hash_id', 'on_full']:
+            assert getattr(empty, attr) == getattr(bloom, attr), attr
+        assert empty.hash_func is hash_func
+        assert empty.stats()['k'] == bloom.stats()['k']
+        # always compatible with the original
+        assert empty | bloom == bloom and not empty & bloom
+        empty.update(range(100))
+        assert empty == bloom and 1 not in Bloom.empty_like(bloom)
+
+    for other in [None, 'foo', CountingBloom(100, 0.01)]:
+        try:
+            Bloom.empty_like(other)
+        except TypeError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def rebuild_compatible_with():
+    template = Bloom(10_000, 0.001, sha_based, blocked=True, hash_id='sha',
+                     warn_at_fill=0.9, on_full='reject')
+    template.update(range(-100, 0))
+    for bloom in [Bloom(1000, 0.1), Bloom(100, 0.01, sha_based, blocked=True)]:
+        bloom.update(range(100))
+        rebuilt = bloom.rebuild_compatible_with(template, range(100))
+        assert rebuilt.size_in_bits == template.size_in_bits
+        assert rebuilt.expected_items == 10_000
+        assert rebuilt.false_positive_rate == 0.001
+        assert rebuilt.blocked and rebuilt.hash_func is sha_based
+        assert rebuilt.hash_id == 'sha' and rebuilt.on_full == 'reject'
+        assert all(i in rebuilt for i in range(100)) and -1 not in rebuilt
+        assert rebuilt | template == template.union(range(100))
+        # the original filter is left as it was
+        assert bloom.size_in_bits < template.size_in_bits and 99 in bloom
+
+    for template in [None, 'foo', CountingBloom(100, 0.01)]:
+        try:
+            Bloom(100, 0.01).rebuild_compatible_with(template, [1, 2])
+        except TypeError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def bloom_set():
+    for hash_func in [hash, sha_based]:
+        for is_blocked in [False, True]:
+            shards = [Bloom(1000, 0.01, hash_func, blocked=is_blocked)
+                      for _ in range(20)]
+            for i, shard in enumerate(shards):
+                shard.update(range(i * 100, i * 100 + 100))
+            filters = BloomSet(shards)
+            assert len(filters) == 20 and filters[-1] is shards[-1]
+            for key in [0, 550, 1999, 'foo']:
+                assert filters.which_contain(key) == \
+                    [i for i, shard in enumerate(shards) if key in shard]
+            # the filters aren't copied
+            shards[3].add('foo')
+            assert 3 in filters.which_contain('foo')
+
+    assert BloomSet().which_contain('foo') == []
+    filters = BloomSet()
+    filters.append(Bloom(1000, 0.01))
+    for incompatible in [Bloom(1000, 0.02), Bloom(1000, 0.01, sha_based),
+                         Bloom(1000, 0.01, blocked=True)]:
+        try:
+            filters.append(incompatible)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+    try:
+        filters[1]
+    except IndexError:
+        pass
+    else:
+        assert False, "should have raised"
+
+    # a filter resized after it was appended is no longer compatible
+    filters.append(Bloom(1000, 0.01))
+    filters[1].reconfigure(10, 0.01)
+    try:
+        filters.which_contain('foo')
+    except ValueError:
+        pass
+    else:
+        assert False, "should have raised"
+
+
+def union_view():
+    for hash_func in [hash, sha_based]:
+        for is_blocked in [False, True]:
+            shards = [Bloom(1000, 0.01, hash_func, blocked=is_blocked)
+                      for _ in range(5)]
+            for i, shard in enumerate(shards):
+                shard.update(range(i * 100, i * 100 + 100))
+            view = UnionView(shards)
+            union = shards[0].union(*shards[1:])
+            assert len(view) == 5
+            assert all(i in view for i in range(500))
+            for key in range(-1000, 0):
+                assert (key in view) == (key in union)
+            # the filters aren't copied
+            shards[4].add('foo')
+            assert 'foo' in view
+
+    view = UnionView([])
+    assert len(view) == 0 and 'foo' not in view
+    assert repr(UnionView(iter([Bloom(10, 0.1)]))) == "<UnionView filters=1>"
+
+    for filters in [[Bloom(1000, 0.01), Bloom(1000, 0.02)],
+                    [Bloom(1000, 0.01), Bloom(1000, 0.01, sha_based)],
+                    [Bloom(1000, 0.01), Bloom(1000, 0.01, blocked=True)]]:
+        try:
+            UnionView(filters)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+    try:
+        UnionView([Bloom(1000, 0.01), 'foo'])
+    except TypeError:
+        pass
+    else:
+        assert False, "should have raised"
+
+    filters = [Bloom(1000, 0.01), Bloom(1000, 0.01)]
+    view = UnionView(filters)
+    filters[1].reconfigure(10, 0.01)
+    try:
+        'foo' in view
+    except ValueError:
+        pass
+    else:
+        assert False, "should have raised"
+
+
+def warn_at_fill():
+    def fill_warnings(f):
+        with warnings.catch_warnings(record=True) as caught:
+            warnings.simplefilter('always')
+            f()
+        return [w for w in caught if issubclass(w.category, UserWarning)]
+
+    for hash_func in [hash, sha_based]:
+        bloom = Bloom(1000, 0.01, hash_func, warn_at_fill=0.5)
+        assert not fill_warnings(lambda: bloom.update(range(800)))
+        caught = fill_warnings(lambda: bloom.update(range(800, 1200)))
+        assert len(caught) == 1 and 'warn_at_fill=0.5' in str(caught[0].message)
+        assert sum(bloom[i] for i in range(bloom.size_in_bits)) \
+            >= bloom.size_in_bits / 2
+        # only the first time
+        assert not fill_warnings(lambda: bloom.update(range(1200, 2000)))
+        # until the filter is emptied
+        bloom.clear()
+        assert not fill_warnings(lambda: bloom.add_batch(range(800)))
+        assert len(fill_warnings(lambda: bloom.add_batch(range(800, 1200)))) == 1
+
+    # bits set by a union are counted as well
+    bloom = Bloom(1000, 0.01, warn_at_fill=0.5)
+    full = Bloom(1000, 0.01)
+    full.update(range(1200))
+    bloom |= full
+    assert len(fill_warnings(lambda: bloom.add('foo'))) == 1
+
+    assert not fill_warnings(lambda: Bloom(1000, 0.01).update(range(5000)))
+    for threshold in [0.0, 1.5, float('nan')]:
+        try:
+            Bloom(1000, 0.01, warn_at_fill=threshold)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def bit_count():
+    # approx_items comes from a running count of the set bits, which has
+    # to agree with a fresh count of them after every kind of change
+    def check(bloom):
+        recounted = Bloom.load_bytes(bloom.save_bytes(), sha_based)
+        assert bloom.approx_items == recounted.approx_items
+        assert bool(bloom) == any(bloom[i] for i in range(bloom.size_in_bits))
+
+    bloom = Bloom(1000, 0.01, sha_based)
+    other = Bloom(1000, 0.01, sha_based)
+    other.update(range(500, 1500))
+    check(bloom)
+    bloom.update(range(1000))
+    bloom.add_batch(range(900, 1100), count_collisions=True)
+    bloom.add_hash(sha_based('foo'))
+    check(bloom)
+    check(bloom | other)
+    check(bloom & other)
+    check(bloom.intersection(range(200)))
+    bloom.intersection_update(range(200))
+    check(bloom)
+    bloom.clear()
+    check(bloom)
+    expected_items = 1000
+    while bloom.size_in_bits % 16 != 0:
+        expected_items += 1
+        bloom.reconfigure(expected_items, 0.01)
+    check(bloom)
+    bloom.update(range(1000))
+    bloom.shrink(2)
+    check(bloom)
+    check(Bloom.from_hashes(array('q', range(100)), 1000, 0.01, sha_based))
+
+
+def remaining_capacity():
+    for is_blocked in [False, True]:
+        bloom = Bloom(10_000, 0.01, sha_based, blocked=is_blocked)
+        capacity = bloom.remaining_capacity()
+        # k and the size are rounded, so the capacity is a bit off
+        assert 9_900 < capacity < 10_100
+        bloom.update(range(4_000))
+        remaining = bloom.remaining_capacity()
+        assert abs(remaining - (capacity - 4_000)) < 200
+        bloom.update(range(4_000, 4_000 + remaining))
+        assert bloom.remaining_capacity() < 200
+        bloom.update(range(20_000, 40_000))
+        assert bloom.remaining_capacity() == 0
+        bloom.clear()
+        assert bloom.remaining_capacity() == capacity
+
+    # an empty filter has room for about what it was designed for
+    for rate in [0.5, 0.1, 0.001, 1e-6]:
+        assert 9_900 < Bloom(10_000, rate).remaining_capacity() < 10_100
+
+
+def stats():
+    for is_blocked in [False, True]:
+        bloom = Bloom(10_000, 0.01, sha_based, blocked=is_blocked)
+        stats = bloom.stats()
+        assert stats['size_in_bits'] == bloom.size_in_bits
+        assert stats['k'] == bloom.optimal_num_hashes() == 7
+        assert stats['bits_set'] == 0 and stats['fill_ratio'] == 0.0
+        assert stats['approx_items'] == 0.0
+        assert stats['current_false_positive_rate'] == 0.0
+        assert stats['false_positive_rate'] == 0.01
+
+        bloom.update(range(10_000))
+        stats = bloom.stats()
+        assert stats['bits_set'] == sum(bloom[i] for i in range(bloom.size_in_bits))
+        assert stats['fill_ratio'] == stats['bits_set'] / bloom.size_in_bits
+        assert stats['approx_items'] == bloom.approx_items
+        # a uniform hash sets about as many bits as expected
+        assert abs(stats['bits_set'] / stats['expected_bits_set'] - 1) < 0.02
+        assert 0.005 < stats['current_false_positive_rate'] < 0.015
+        measured = bloom.measured_false_positive_rate(range(-100_000, 0))
+        assert abs(measured - stats['current_false_positive_rate']) < 0.003
+
+    # a loaded filter may claim any expected_items
+    data = bytearray(Bloom(1000, 0.01, sha_based).save_bytes())
+    data[24:32] = (2**64 - 1).to_bytes(8, 'little')
+    stats = Bloom.load_bytes(bytes(data), sha_based).stats()
+    assert stats['expected_bits_set'] <= stats['size_in_bits']
+
+
+def large_merge():
+    # filters of more than 4 MiB are combined on several threads, which has
+    # to give the same bits as combining them one byte at a time
+    def body(bloom):
+        return int.from_bytes(bloom.save_bytes()[-(bloom.size_in_bits // 8):], 'little')
+
+    a = Bloom(10_000_000, 0.01, sha_based)
+    b = Bloom(10_000_000, 0.01, sha_based)
+    assert a.size_in_bits > 8 * 4 * 2**20
+    a.update(range(20_000))
+    b.update(range(10_000, 30_000))
+    assert body(a | b) == body(a) | body(b)
+    assert body(a & b) == body(a) & body(b)
+    union = a.copy()
+    union.update(b)
+    assert union.approx_items == (a | b).approx_items
+    assert union == Bloom.load_bytes(union.save_bytes(), sha_based)
+    assert all(i in union for i in range(30_000))
+    a &= b
+    assert all(i in a for i in range(10_000, 20_000))
+    assert a == Bloom.load_bytes(a.save_bytes(), sha_based)
+
+
+def hash_id():
+    def same_as_sha_based(obj):
+        return sha_based(obj)
+
+    bloom = Bloom(1000, 0.01, sha_based, hash_id='sha')
+    bloom.update(range(100))
+    assert bloom.hash_id == 'sha' and Bloom(1000, 0.01).hash_id is None
+
+    # a different object with the same hash_id is compatible
+    other = Bloom(1000, 0.01, same_as_sha_based, hash_id='sha')
+    other.update(range(100, 200))
+    union = bloom | other
+    assert all(i in union for i in range(200))
+    assert other.copy() <= union and BloomSet([bloom, other])
+
+    # the hash_id survives serialization, and the loaded filter can be
+    # combined with filters using a different hash_func object
+    data = bloom.save_bytes()
+    assert len(data) == 24 + 2 + 3 + 29 + bloom.size_in_bits // 8
+    loaded = Bloom.load_bytes(data, same_as_sha_based)
+    assert loaded.hash_id == 'sha' and loaded == bloom
+    assert b''.join(bloom.iter_chunks(7)) == data
+    other |= loaded
+    assert all(i in other for i in range(200))
+
+    # without hash_ids on both sides, the functions have to be identical,
+    # and different hash_ids are never compatible
+    for incompatible in [Bloom(1000, 0.01, same_as_sha_based),
+                         Bloom(1000, 0.01, same_as_sha_based, hash_id='other'),
+                         Bloom(1000, 0.01, sha_based, hash_id='other')]:
+        try:
+            bloom | incompatible
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+    assert bloom == Bloom(1000, 0.01, sha_based) | bloom
+
+    for args in [(hash, 'builtin'), (None, 'builtin'), (sha_based, 'x' * 70_000)]:
+        try:
+            Bloom(1000, 0.01, args[0], hash_id=args[1])
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+    for corrupt in [data[:26], data[:26] + b'\xff\xfe\xfd' + data[29:],
+                    data[:5] + b'\x00' + data[6:]]:
+        try:
+            Bloom.load_bytes(corrupt, sha_based)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def on_full():
+    for is_blocked in [False, True]:
+        bloom = Bloom(1000, 0.01, blocked=is_blocked, on_full='reject')
+        assert bloom.on_full == 'reject'
+        try:
+            bloom.update(range(2000))
+        except RuntimeError:
+            pass
+        else:
+            assert False, "should have raised"
+        # the items up to about expected_items made it in
+        assert 900 < bloom.approx_items < 1100
+        assert all(i in bloom for i in range(900))
+        try:
+            bloom.add_hash(hash('foo'))
+        except RuntimeError:
+            pass
+        else:
+            assert False, "should have raised"
+        bloom.clear()
+        bloom.add('foo')
+
+        bloom = Bloom(1000, 0.01, blocked=is_blocked, on_full='rotate')
+        with warnings.catch_warnings(record=True) as caught:
+            warnings.simplefilter('always')
+            bloom.add_batch(range(2500))
+        assert len(caught) == 2
+        assert all(issubclass(w.category, UserWarning) for w in caught)
+        assert 2499 in bloom and bloom.approx_items < 600
+
+        bloom = Bloom(1000, 0.01, blocked=is_blocked)
+        assert bloom.on_full == 'ignore'
+        bloom.update(range(2000))
+        assert bloom.approx_items > 1500
+
+    try:
+        Bloom(1000, 0.01, on_full='drop')
+    except ValueError:
+        pass
+    else:
+        assert False, "should have raised"
+
+
+def measured_false_positive_rate():
+    for hash_func in [hash, sha_based]:
+        for is_blocked in [False, True]:
+            bloom = Bloom(10_000, 0.01, hash_func, blocked=is_blocked)
+            bloom.update(range(10_000))
+            rate = bloom.measured_false_positive_rate(range(-100_000, 0))
+            assert 0.005 < rate < 0.02
+            assert bloom.measured_false_positive_rate(range(10)) == 1.0
+            assert Bloom(10, 0.01).measured_false_positive_rate(['foo']) == 0.0
+
+    try:
+        Bloom(10, 0.01).measured_false_positive_rate([])
+    except ValueError:
+        pass
+    else:
+        assert False, "should have raised"
+
+
+def load_from():
+    class Trickle:
+        # returns at most 5 bytes per read, like a slow socket
+        def __init__(self, data):
+            self.data = io.BytesIO(data)
+
+        def read(self, size):
+            return self.data.read(min(size, 5))
+
+    for is_blocked in [False, True]:
+        bloom = Bloom(1000, 0.01, sha_based, blocked=is_blocked, hash_id='sha')
+        bloom.update(range(500))
+        f = io.BytesIO()
+        bloom.save_to(f)
+        data = f.getvalue()
+        assert len(data) == len(bloom.save_bytes()) + 4
+        assert int.from_bytes(data[-4:], 'little') == zlib.crc32(data[:-4])
+
+        for reader in [io.BytesIO(data), Trickle(data)]:
+            loaded = Bloom.load_from(reader, sha_based)
+            assert loaded == bloom
+            assert loaded.hash_id == 'sha'
+            assert loaded.blocked == is_blocked
+        assert Bloom.load_bytes(data, sha_based) == bloom
+
+        compressed = io.BytesIO()
+        with gzip.GzipFile(fileobj=compressed, mode='wb') as g:
+            bloom.save_to(g)
+        compressed.seek(0)
+        with gzip.GzipFile(fileobj=compressed, mode='rb') as g:
+            assert Bloom.load_from(g, sha_based) == bloom
+
+        corrupt = bytearray(data)
+        corrupt[100] ^= 1
+        for bad in [bytes(corrupt), data[:-1], data[:50], data + b'x']:
+            loads = [lambda d: Bloom.load_bytes(d, sha_based)]
+            # a stream may go on after the filter, see below
+            if bad != data + b'x':
+                loads.append(lambda d: Bloom.load_from(io.BytesIO(d), sha_based))
+            for load in loads:
+                try:
+                    load(bad)
+                except ValueError:
+                    pass
+                else:
+                    assert False, "should have raised"
+
+        # load_from reads just the filter, so a stream can hold several,
+        # and it doesn't wait for more data after the end of the filter
+        f = io.BytesIO()
+        bloom.save_to(f)
+        other = Bloom(100, 0.1, sha_based)
+        other.save_to(f)
+        f.write(b'rest')
+        f.seek(0)
+        assert Bloom.load_from(Trickle(f.read()), sha_based) == bloom
+        f.seek(0)
+        assert Bloom.load_from(f, sha_based) == bloom
+        assert Bloom.load_from(f, sha_based) == other
+        assert f.read() == b'rest'
+        assert Bloom.load_from(io.BytesIO(bytes(corrupt)), sha_based,
+                               verify=False) != bloom
+
+        plain = bloom.save_bytes()
+        try:
+            Bloom.load_from(io.BytesIO(plain), sha_based)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+        assert Bloom.load_from(io.BytesIO(plain), sha_based, verify=False) == bloom
+
+    for reader in [io.StringIO('abc'), Trickle(b'')]:
+        try:
+            Bloom.load_from(reader, sha_based)
+        except (TypeError, ValueError):
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def shards():
+    for is_blocked in [False, True]:
+        bloom = Bloom(1000, 0.01, sha_based, blocked=is_blocked, hash_id='sha')
+        bloom.update(range(500))
+        data = bloom.save_bytes()
+        for n in [1, 2, 3, 7, len(data)]:
+            pieces = bloom.to_shards(n)
+            assert len(pieces) == n
+            assert b''.join(piece[24:] for piece in pieces) == data
+            sizes = [len(piece) - 24 for piece in pieces]
+            assert max(sizes) - min(sizes) <= 1
+            loaded = Bloom.from_shards(pieces, sha_based)
+            assert loaded == bloom and loaded.hash_id == 'sha'
+            assert loaded.blocked == is_blocked
+        assert Bloom.from_shards(iter(bloom.to_shards(3)), sha_based) == bloom
+
+    pieces = bloom.to_shards(4)
+    other = Bloom(1000, 0.01, sha_based, blocked=True).to_shards(4)
+    corrupt = bytearray(pieces[2])
+    corrupt[-1] ^= 1
+    for bad in [[], pieces[:3], pieces[1:], pieces[::-1], pieces + pieces[-1:],
+                pieces[:2] + other[2:], pieces[:2] + [bytes(corrupt)] + pieces[3:],
+                pieces[:3] + [pieces[3][:-1]], [b'RBLM'] + pieces[1:]]:
+        try:
+            Bloom.from_shards(bad, sha_based)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+    for bad in [[pieces[0], 'foo'], [bytearray(pieces[0])], None]:
+        try:
+            Bloom.from_shards(bad, sha_based)
+        except TypeError:
+            pass
+        else:
+            assert False, "should have raised"
+
+    for n in [0, len(bloom.save_bytes()) + 1]:
+        try:
+            bloom.to_shards(n)
+        except ValueError:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def progress():
+    for every, expected in [(3, [3, 6, 9, 10]), (5, [5, 10]), (100, [10])]:
+        calls = []
+        bloom = Bloom(1000, 0.01)
+        bloom.add_batch(range(10), progress=calls.append, progress_every=every)
+        assert calls == expected and all(i in bloom for i in range(10))
+
+        calls = []
+        bloom = Bloom.from_hashes(array('q', range(10)), 1000, 0.01,
+                                  progress=calls.append, progress_every=every)
+        assert calls == expected and bloom.contains_hash(9)
+
+    calls = []
+    Bloom(1000, 0.01).add_batch([], progress=calls.append)
+    Bloom.from_hashes(array('q'), 1000, 0.01, progress=calls.append)
+    assert calls == []
+
+    i = 0
+    while os.path.exists(f'test{i}.txt'):
+        i += 1
+    filename = f'test{i}.txt'
+    try:
+        with open(filename, 'w') as f:
+            f.write('a\nb\n\nc\nd\n')
+        calls = []
+        Bloom(1000, 0.01).add_lines(filename, skip_empty=True,
+                                    progress=calls.append, progress_every=2)
+        assert calls == [2, 4]
+    finally:
+        os.remove(filename)
+
+    # an exception raised by the callback stops the operation
+    def stop(n):
+        raise KeyboardInterrupt
+    bloom = Bloom(1000, 0.01)
+    try:
+        bloom.add_batch(range(10), progress=stop, progress_every=4)
+    except KeyboardInterrupt:
+        pass
+    else:
+        assert False, "should have raised"
+    assert 3 in bloom and 4 not in bloom
+
+    for kwargs, error in [({'progress_every': 0}, ValueError), ({'progress': 1}, TypeError)]:
+        try:
+            Bloom(1000, 0.01).add_batch(range(10), **kwargs)
+        except error:
+            pass
+        else:
+            assert False, "should have raised"
+
+
+def structurally_equal():
+    bloom = Bloom(1000, 0.01, sha_based)
+    bloom.update(range(100))
+    other = Bloom.load_bytes(bloom.save_bytes(), lambda x: sha_based(x))
+    try:
+        bloom == other
+    except ValueError:
+        pass
+    else:
+        assert False, "should have raised"
+    assert bloom.structurally_equal(other) and other.structurally_equal(bloom)
+
+    other.add('foo')
+    assert not bloom.structurally_equal(other)
+    assert not Bloom(1000, 0.01).structurally_equal(Bloom(1000, 0.02))
+    assert not Bloom(1000, 0.01).structurally_equal(Bloom(1000, 0.01, blocked=True))
+    try:
+        bloom.structurally_equal(set())
+    except TypeError:
+        pass
+    else:
+        assert False, "should have raised"
+
 def api_suite():
-    assert repr(Bloom(27_000, 0.0317)) == "<Bloom size_in_bits=193960 approx_items=0.0>"
+    assert repr(Bloom(27_000, 0.0317)) == "<Bloom size_in_bits=193968 approx_items=0.0>"
+    html = Bloom(27_000, 0.0317)._repr_html_()
+    assert '23.7 KiB' in html and '0.0%' in html and '0.0317' in html
+    bloom = Bloom(1000, 0.01)
+    bloom.update(range(1000))
+    assert '<table>' in bloom._repr_html_() and '1000 expected' in bloom._repr_html_()
+    assert '1.1 GiB' in Bloom(10**9, 0.01)._repr_html_()
     assert Bloom(1140, 0.999).hash_func == hash
     assert Bloom(102, 0.01, hash_func=hash).hash_func is hash
     assert Bloom(103100, 0.51, hash_func=sha_based).hash_func is sha_based
@@ -125,6 +1894,59 @@ def api_suite():
     test_bloom(Bloom(2837, 0.5, hash_func=hash))
 
     circular_ref()
+    capacity_for()
+    meets_design_rate()
+    rate_for_memory()
+    degenerate_parameters()
+    hash_quality()
+    false_positive_rate()
+    add_batch()
+    operators_with_iterables()
+    counting_bloom()
+    stable_bloom()
+    count_min_bloom()
+    precomputed_hashes()
+    bit_inspection()
+    indices_for()
+    truncated_bytes()
+    blocked()
+    legacy_format()
+    merge()
+    hash_errors()
+    with_size()
+    tunables_round_trip()
+    approx_items()
+    delta()
+    journal()
+    inspect_bytes()
+    index_uniformity()
+    retune()
+    digest_hashes()
+    from_hashes()
+    iter_chunks()
+    structurally_equal()
+    shrink()
+    contains_fraction()
+    fingerprint()
+    add_lines()
+    reconfigure()
+    add_all()
+    exclusions()
+    empty_like()
+    rebuild_compatible_with()
+    bloom_set()
+    union_view()
+    warn_at_fill()
+    bit_count()
+    remaining_capacity()
+    stats()
+    large_merge()
+    hash_id()
+    on_full()
+    measured_false_positive_rate()
+    load_from()
+    shards()
+    progress()
 
     print('All API tests passed')
 